@@ -0,0 +1,199 @@
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+
+use std::{
+    fs,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::crypto::Cipher;
+use crate::errors::DatabaseError;
+use crate::storage;
+
+// associated data for every sealed line, so a sealed record from another file doesn't pass as one
+const AUDIT_AAD: &[u8] = b"audit.log";
+
+// One JSON object per line in audit.log, or on an encrypted database each one sealed and written
+// as base64. Lines are only ever appended, nothing but a rekey rewrites the file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    // seconds since the unix epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub collection: Option<String>,
+    pub operation: String,
+    pub key: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+// Which entries AUDIT returns, every filter that is set has to match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub collection: Option<String>,
+    pub operation: Option<String>,
+    pub key: Option<String>,
+    // only the most recent this many
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditLog {
+    path: String,
+    #[serde(skip)]
+    cipher: Option<Cipher>,
+}
+
+impl AuditEntry {
+    pub fn new(user: &str, collection: Option<String>, operation: &str, key: Option<String>, error: Option<&DatabaseError>) -> AuditEntry {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        AuditEntry {
+            timestamp,
+            user: user.to_string(),
+            collection,
+            operation: operation.to_string(),
+            key,
+            success: error.is_none(),
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let matches = |wanted: &Option<String>, found: Option<&String>| match wanted {
+            Some(wanted) => found == Some(wanted),
+            None => true,
+        };
+        matches(&self.user, Some(&entry.user))
+            && matches(&self.collection, entry.collection.as_ref())
+            && self.operation.as_ref().is_none_or(|operation| operation.eq_ignore_ascii_case(&entry.operation))
+            && matches(&self.key, entry.key.as_ref())
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: String, cipher: Option<Cipher>) -> AuditLog {
+        AuditLog { path, cipher }
+    }
+
+    pub fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+    }
+
+    fn log_path(&self) -> String {
+        format!("{}/audit.log", self.path)
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> Result<(), DatabaseError> {
+        let line = AuditLog::encode(entry, self.cipher.as_ref())?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path())?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // Oldest first
+    pub fn read(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut entries = self.entries()?;
+        entries.retain(|entry| filter.matches(entry));
+        if let Some(limit) = filter.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(entries)
+    }
+
+    // Writes every entry under the new cipher to audit.log.rekey, for finish_rekey to move into place
+    pub(crate) fn rekey(&self, cipher: Option<&Cipher>) -> Result<(), DatabaseError> {
+        let mut contents = String::new();
+        for entry in self.entries()? {
+            contents.push_str(&AuditLog::encode(&entry, cipher)?);
+        }
+        storage::write_atomic(format!("{}.rekey", self.log_path()), contents.as_bytes())
+    }
+
+    // Whether every line of the file can be read with the cipher, which is how finish_rekey tells a
+    // staged audit.log from a rekey that went through from one that didn't
+    pub(crate) fn readable(contents: &[u8], cipher: Option<&Cipher>) -> bool {
+        std::str::from_utf8(contents).is_ok_and(|contents| contents.lines()
+            .filter(|line| !line.trim().is_empty())
+            .all(|line| AuditLog::decode(line, cipher).is_ok()))
+    }
+
+    fn entries(&self) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let contents = match fs::read_to_string(self.log_path()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        for line in contents.split_inclusive('\n') {
+            let start = offset;
+            offset += line.len();
+            if line.trim().is_empty() {
+                continue
+            }
+            let entry = AuditLog::decode(line.trim_end(), self.cipher.as_ref()).map_err(|reason| DatabaseError::CorruptData {
+                file: self.log_path(),
+                offset: start as u64,
+                reason,
+            })?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn encode(entry: &AuditEntry, cipher: Option<&Cipher>) -> Result<String, DatabaseError> {
+        let json = serde_json::to_string(entry)?;
+        let mut line = match cipher {
+            Some(cipher) => base64::engine::general_purpose::STANDARD.encode(cipher.seal(json.as_bytes(), AUDIT_AAD)?),
+            None => json,
+        };
+        line.push('\n');
+        Ok(line)
+    }
+
+    fn decode(line: &str, cipher: Option<&Cipher>) -> Result<AuditEntry, String> {
+        let json = match cipher {
+            Some(cipher) => base64::engine::general_purpose::STANDARD.decode(line).ok()
+                .and_then(|sealed| cipher.open(&sealed, AUDIT_AAD))
+                .ok_or("failed to decrypt")?,
+            None => line.as_bytes().to_vec(),
+        };
+        serde_json::from_slice(&json).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tempdir::TempDir;
+
+    use crate::audit::{AuditEntry, AuditFilter, AuditLog};
+    use crate::errors::DatabaseError;
+
+    #[test]
+    fn audit_log_filters() {
+        let dir = TempDir::new("audit").unwrap();
+        let log = AuditLog::new(dir.path().to_str().unwrap().to_string(), None);
+        log.record(&AuditEntry::new("alice", Some("orders".to_string()), "INSERT", Some("a".to_string()), None)).unwrap();
+        log.record(&AuditEntry::new("bob", Some("orders".to_string()), "DELETE", Some("a".to_string()), None)).unwrap();
+        log.record(&AuditEntry::new("bob", Some("orders".to_string()), "DELETE", Some("b".to_string()),
+            Some(&DatabaseError::ValueNotFound("b".to_string())))).unwrap();
+
+        let deleted_a = log.read(&AuditFilter { operation: Some("delete".to_string()), key: Some("a".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(deleted_a.len(), 1);
+        assert_eq!(deleted_a[0].user, "bob");
+
+        let latest = log.read(&AuditFilter { limit: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(latest[0].key.as_deref(), Some("b"));
+        assert!(!latest[0].success);
+        assert_eq!(log.read(&AuditFilter::default()).unwrap().len(), 3);
+    }
+}
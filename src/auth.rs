@@ -1,17 +1,64 @@
 use serde::{Serialize, Deserialize};
 use bcrypt::{hash, verify, DEFAULT_COST};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
 
+use crate::crypto::{self, Cipher};
 use crate::errors::DatabaseError;
 use crate::session::Session;
+use crate::storage;
+use crate::database::DatabaseState;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+// users.log starts with the magic and then the bincode AuthManager. Files from before grants,
+// lockouts and API tokens have no magic and hold only the users, they're upgraded when loaded
+const USERS_MAGIC: &[u8; 8] = b"DBUSERS1";
+
+// API tokens look like dbt_<id>_<secret>, the id finds the token and the secret proves it
+const TOKEN_PREFIX: &str = "dbt_";
+
+// how long a token from POST /sessions lasts
+const SESSION_TOKEN_SECONDS: u64 = 60 * 60;
+
+// Every failed login gets this, whether the username exists, the password was wrong or the
+// account is locked, so none of them can be told apart from outside
+const LOGIN_FAILED: &str = "Invalid username or password, or the account is locked";
+
+// After this many failures in a row the account locks for LOCKOUT_SECONDS, doubling with every
+// further failure up to MAX_LOCKOUT_SECONDS
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const LOCKOUT_SECONDS: u64 = 30;
+const MAX_LOCKOUT_SECONDS: u64 = 60 * 60;
+
+// checked against when the username doesn't exist so that takes as long as a wrong password
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+// A user's role, which decides what they can do in collections nobody granted them anything on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Permissions {
     Admin(),
     User(),
     Guest(),
+    // nothing at all outside of their grants
+    Restricted(),
+}
+
+// Levels of access to a collection, each one includes the ones before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+    // changing grants on the collection
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Grantee {
+    User(String),
+    Role(Permissions),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,71 +66,601 @@ pub struct User {
     pub username: String,
     password_hash: String,
     pub permissions: Permissions,
+    // failed logins since the last one that worked
+    pub failed_attempts: u32,
+    // unix time the lockout ends, 0 when there isn't one
+    pub locked_until: u64,
+}
+
+// Only a hash of the secret is kept, the token itself is shown once when it's made. The secret is
+// 32 random bytes so a plain SHA-256 is enough, there's nothing to brute force
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    pub owner: String,
+    secret_hash: String,
+    // unix times
+    pub created: u64,
+    pub expires: u64,
+    pub read_only: bool,
+}
+
+// A login that has been started but not finished. Checking the password is bcrypt, which is slow
+// and doesn't need the user store, so front ends that share the database between threads do it
+// between begin_login and finish_login without holding the database
+pub struct LoginAttempt {
+    username: String,
+    password: String,
+    // the user's hash, or the dummy one when there's no such user
+    hash: String,
+    // the password is one of the user's API tokens, which are cheap to check
+    token: bool,
+    verified: bool,
+}
+
+impl LoginAttempt {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn check(&mut self) {
+        if !self.token {
+            self.verified = AuthManager::verify_password(&self.password, &self.hash);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UserV1 {
+    username: String,
+    password_hash: String,
+    permissions: Permissions,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AuthManager {
     users: HashMap<String, User>,
-    current: Option<String>,
+    // bearer tokens handed out to logged in clients along with when they expire, they don't
+    // outlast the process either
+    #[serde(skip)]
+    session_tokens: HashMap<String, (Session, u64)>,
+    // access to individual collections on top of what the role already allows
+    grants: HashMap<Grantee, BTreeMap<String, Access>>,
+    // by token id
+    api_tokens: HashMap<String, ApiToken>,
+    // users.log is sealed with it when the database is encrypted
+    #[serde(skip)]
+    cipher: Option<Cipher>,
+}
+
+// it also kept the last user to log in, which nothing uses
+#[derive(Deserialize)]
+struct LegacyAuthManager {
+    users: HashMap<String, UserV1>,
+    _current: Option<String>,
+}
+
+impl From<UserV1> for User {
+    fn from(user: UserV1) -> User {
+        User { username: user.username, password_hash: user.password_hash, permissions: user.permissions, failed_attempts: 0, locked_until: 0 }
+    }
+}
+
+impl Permissions {
+    // What the role can do in every collection
+    pub fn access(&self) -> Option<Access> {
+        match self {
+            Permissions::Admin() => Some(Access::Admin),
+            Permissions::User() => Some(Access::Write),
+            Permissions::Guest() => Some(Access::Read),
+            Permissions::Restricted() => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Permissions {
+    type Err = DatabaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(Permissions::Admin()),
+            "user" => Ok(Permissions::User()),
+            "guest" => Ok(Permissions::Guest()),
+            "restricted" => Ok(Permissions::Restricted()),
+            _ => Err(DatabaseError::syntax(format!("{} is not a role, expected admin, user, guest or restricted", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for Access {
+    type Err = DatabaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "admin" => Ok(Access::Admin),
+            _ => Err(DatabaseError::syntax(format!("{} is not an access level, expected read, write or admin", s))),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permissions::Admin() => write!(f, "admin"),
+            Permissions::User() => write!(f, "user"),
+            Permissions::Guest() => write!(f, "guest"),
+            Permissions::Restricted() => write!(f, "restricted"),
+        }
+    }
+}
+
+impl fmt::Display for Grantee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Grantee::User(user) => write!(f, "{}", user),
+            Grantee::Role(role) => write!(f, "role {}", role),
+        }
+    }
 }
 
 impl AuthManager {
     pub fn create_session(user: &User) -> Session {
-        Session{ user: user.username.clone(), permissions: user.permissions.clone()}
+        Session{ user: user.username.clone(), permissions: user.permissions.clone(), state: DatabaseState::Unselected(), transaction: None, read_only: false }
     }
 
-    pub fn new(path: &str) -> Result<AuthManager, DatabaseError> {
-
-        let manager = AuthManager{ users: HashMap::new(), current: None };
-        let encoded : Vec<u8> = bincode::serialize(&manager)?;
+    pub fn new(path: &str, cipher: Option<Cipher>) -> Result<AuthManager, DatabaseError> {
+        let manager = AuthManager{ users: HashMap::new(), session_tokens: HashMap::new(), grants: HashMap::new(), api_tokens: HashMap::new(), cipher };
+        manager.save(path)?;
+        Ok(manager)
+    }
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(format!("{}/users.log", path))?;
+    pub fn load(path: &str, cipher: Option<Cipher>) -> Result<AuthManager, DatabaseError> {
+        let file = format!("{}/users.log", path);
+        let contents = fs::read(&file)?;
+        // an empty users.log is a directory that never had a user store written to it
+        if contents.is_empty() {
+            return AuthManager::new(path, cipher)
+        }
+        let contents = crypto::open_file(cipher.as_ref(), &file, contents)?;
 
-        file.write_all(&encoded)?;
-        std::mem::drop(file);
+        let decoded = match contents.strip_prefix(USERS_MAGIC.as_slice()) {
+            Some(encoded) => bincode::deserialize(encoded),
+            None => bincode::deserialize::<LegacyAuthManager>(&contents).map(|legacy| AuthManager {
+                users: legacy.users.into_iter().map(|(name, user)| (name, user.into())).collect(),
+                session_tokens: HashMap::new(),
+                grants: HashMap::new(),
+                api_tokens: HashMap::new(),
+                cipher: None,
+            }),
+        };
+        let mut manager: AuthManager = decoded.map_err(|e| DatabaseError::CorruptData { file, offset: 0, reason: e.to_string() })?;
+        manager.cipher = cipher;
         Ok(manager)
     }
 
-    pub fn login(&mut self, username: String, password : String) -> Result<Session, DatabaseError> {
-        match self.users.get(&username) {
-            Some(user) => {
-                match AuthManager::verify_password(password, &user.password_hash) {
-                    true => {
-                        self.current = Some(user.username.clone());
-                        Ok(AuthManager::create_session(user))
-                    }
-                    false => Err(DatabaseError::UserError("Incorrect Password".to_string())),
+    fn save(&self, path: &str) -> Result<(), DatabaseError> {
+        self.save_as(&format!("{}/users.log", path), self.cipher.as_ref())
+    }
+
+    // Rekeying writes the users under the new key somewhere else first
+    pub(crate) fn save_as(&self, file: &str, cipher: Option<&Cipher>) -> Result<(), DatabaseError> {
+        let mut encoded = USERS_MAGIC.to_vec();
+        bincode::serialize_into(&mut encoded, &self)?;
+        storage::write_atomic(file, &crypto::seal_file(cipher, encoded)?)
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+    }
+
+    pub fn login(&mut self, path: &str, username: String, password : String) -> Result<Session, DatabaseError> {
+        self.login_at(path, username, password, AuthManager::now())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+    }
+
+    fn login_at(&mut self, path: &str, username: String, password: String, now: u64) -> Result<Session, DatabaseError> {
+        let mut attempt = self.begin_login_at(username, password, now);
+        attempt.check();
+        self.finish_login_at(path, attempt, now)
+    }
+
+    // An API token belonging to the user works in place of their password
+    pub fn begin_login(&self, username: String, password: String) -> LoginAttempt {
+        self.begin_login_at(username, password, AuthManager::now())
+    }
+
+    fn begin_login_at(&self, username: String, password: String, now: u64) -> LoginAttempt {
+        let token = self.api_token_session(&password, now).is_ok_and(|session| session.user == username);
+        let hash = match self.users.get(&username) {
+            Some(user) => user.password_hash.clone(),
+            None => DUMMY_HASH.get_or_init(|| hash("", DEFAULT_COST).unwrap_or_default()).clone(),
+        };
+        LoginAttempt { username, password, hash, token, verified: false }
+    }
+
+    pub fn finish_login(&mut self, path: &str, attempt: LoginAttempt) -> Result<Session, DatabaseError> {
+        self.finish_login_at(path, attempt, AuthManager::now())
+    }
+
+    fn finish_login_at(&mut self, path: &str, attempt: LoginAttempt, now: u64) -> Result<Session, DatabaseError> {
+        // the token could have been revoked while the attempt was open
+        if attempt.token {
+            return self.api_token_session(&attempt.password, now)
+                .map_err(|_| DatabaseError::UserError(LOGIN_FAILED.to_string()))
+        }
+
+        let Some(user) = self.users.get_mut(&attempt.username) else {
+            return Err(DatabaseError::UserError(LOGIN_FAILED.to_string()))
+        };
+        // a locked account can't get in even with the right password, so guessing gets nowhere.
+        // The password was still checked so the answer takes as long as any other failure. A
+        // password changed while the attempt was open has to be tried again
+        if user.locked_until > now || user.password_hash != attempt.hash {
+            return Err(DatabaseError::UserError(LOGIN_FAILED.to_string()))
+        }
+
+        match attempt.verified {
+            true => {
+                let had_failed = user.failed_attempts > 0;
+                user.failed_attempts = 0;
+                user.locked_until = 0;
+                let session = AuthManager::create_session(user);
+                if had_failed {
+                    self.save(path)?;
                 }
+                Ok(session)
+            }
+            false => {
+                user.failed_attempts += 1;
+                if user.failed_attempts >= MAX_FAILED_ATTEMPTS {
+                    let doublings = (user.failed_attempts - MAX_FAILED_ATTEMPTS).min(16);
+                    user.locked_until = now + (LOCKOUT_SECONDS << doublings).min(MAX_LOCKOUT_SECONDS);
+                }
+                self.save(path)?;
+                Err(DatabaseError::UserError(LOGIN_FAILED.to_string()))
             }
-            None => Err(DatabaseError::UserError("User not found".to_string()))
         }
     }
+
+    pub fn unlock(&mut self, path: &str, username: &str) -> Result<(), DatabaseError> {
+        let user = self.users.get_mut(username).ok_or(DatabaseError::UserError(format!("{} not found", username)))?;
+        user.failed_attempts = 0;
+        user.locked_until = 0;
+        self.save(path)
+    }
     
-    pub fn new_user(&mut self, path: &String, username : &String, password: &String, permissions: Permissions) -> Result<(), DatabaseError> {
+    pub fn new_user(&mut self, path: &str, username : &String, password: &String, permissions: Permissions) -> Result<(), DatabaseError> {
         let password_hash = hash(password, DEFAULT_COST)?;
-        if self.users.get(username).is_some() {
+        if self.users.contains_key(username) {
             return Err(DatabaseError::UserError("Username already taken".to_string()))
         }
 
-        let user = User{ username : username.clone(), password_hash, permissions };
+        let user = User{ username : username.clone(), password_hash, permissions, failed_attempts: 0, locked_until: 0 };
         self.users.insert(username.to_string(), user);
+        self.save(path)
+    }
+
+    // Sorted by username
+    pub fn users(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    // Takes their grants and tokens with them
+    pub fn drop_user(&mut self, path: &str, username: &str) -> Result<(), DatabaseError> {
+        self.check_last_admin(username, None)?;
+        self.users.remove(username).ok_or(DatabaseError::UserError(format!("{} not found", username)))?;
+        self.grants.remove(&Grantee::User(username.to_string()));
+        self.session_tokens.retain(|_, (session, _)| session.user != username);
+        self.api_tokens.retain(|_, token| token.owner != username);
+        self.save(path)
+    }
+
+    // Signs them out of any bearer tokens they had so the old password can't live on through one
+    pub fn set_password(&mut self, path: &str, username: &str, password: &str) -> Result<(), DatabaseError> {
+        let password_hash = hash(password, DEFAULT_COST)?;
+        let user = self.users.get_mut(username).ok_or(DatabaseError::UserError(format!("{} not found", username)))?;
+        user.password_hash = password_hash;
+        self.session_tokens.retain(|_, (session, _)| session.user != username);
+        self.save(path)
+    }
+
+    pub fn set_role(&mut self, path: &str, username: &str, permissions: Permissions) -> Result<(), DatabaseError> {
+        self.check_last_admin(username, Some(&permissions))?;
+        let user = self.users.get_mut(username).ok_or(DatabaseError::UserError(format!("{} not found", username)))?;
+        user.permissions = permissions.clone();
+        for (session, _) in self.session_tokens.values_mut().filter(|(session, _)| session.user == username) {
+            session.permissions = permissions.clone();
+        }
+        self.save(path)
+    }
+
+    // Nobody could manage users any more once the last admin is dropped or demoted
+    fn check_last_admin(&self, username: &str, new_role: Option<&Permissions>) -> Result<(), DatabaseError> {
+        let admins: Vec<&User> = self.users.values().filter(|user| user.permissions == Permissions::Admin()).collect();
+        let is_last = admins.len() == 1 && admins[0].username == username;
+        match (is_last, new_role) {
+            (true, None) => Err(DatabaseError::UserError(format!("{} is the last admin", username))),
+            (true, Some(role)) if role != &Permissions::Admin() => Err(DatabaseError::UserError(format!("{} is the last admin", username))),
+            _ => Ok(()),
+        }
+    }
+
+    // The most the session can do in the collection, from its role and any grants to it or its role.
+    // Without a collection only the role counts. The role is looked up again every time so a
+    // dropped or demoted user loses access straight away, even in sessions they already have
+    pub fn access(&self, session: &Session, collection: Option<&str>) -> Option<Access> {
+        let role = &self.users.get(&session.user)?.permissions;
+        let granted = |grantee: Grantee| collection
+            .and_then(|collection| self.grants.get(&grantee)?.get(collection).copied());
+        [
+            role.access(),
+            granted(Grantee::User(session.user.clone())),
+            granted(Grantee::Role(role.clone())),
+        ].into_iter().flatten().max()
+    }
+
+    pub fn authorize(&self, session: &Session, collection: Option<&str>, access: Access) -> Result<(), DatabaseError> {
+        if session.read_only && access > Access::Read {
+            return Err(DatabaseError::PermissionDenied(format!("{} is logged in with a read-only token", session.user)))
+        }
+        if self.access(session, collection) >= Some(access) {
+            return Ok(())
+        }
+        match collection {
+            Some(collection) => Err(DatabaseError::PermissionDenied(format!("{} does not have {} access to {}", session.user, access, collection))),
+            None => Err(DatabaseError::PermissionDenied(format!("{} does not have {} access to the database", session.user, access))),
+        }
+    }
+
+    pub fn grant(&mut self, path: &str, grantee: Grantee, collection: String, access: Access) -> Result<(), DatabaseError> {
+        if let Grantee::User(user) = &grantee && !self.users.contains_key(user) {
+            return Err(DatabaseError::UserError(format!("{} not found", user)))
+        }
+        let grants = self.grants.entry(grantee).or_default();
+        let current = grants.entry(collection).or_insert(access);
+        *current = access.max(*current);
+        self.save(path)
+    }
+
+    // Carries the collection's grants over to its new name, or throws them away with None so a
+    // collection made later under the old name doesn't inherit them
+    pub fn move_grants(&mut self, path: &str, collection: &str, to: Option<&str>) -> Result<(), DatabaseError> {
+        for grants in self.grants.values_mut() {
+            if let Some(access) = grants.remove(collection) && let Some(to) = to {
+                grants.insert(to.to_string(), access);
+            }
+        }
+        self.grants.retain(|_, grants| !grants.is_empty());
+        self.save(path)
+    }
+
+    // Takes away the level and everything above it, so revoking write leaves read
+    pub fn revoke(&mut self, path: &str, grantee: &Grantee, collection: &str, access: Access) -> Result<(), DatabaseError> {
+        let Some(grants) = self.grants.get_mut(grantee) else { return Ok(()) };
+        match (grants.get(collection), access) {
+            (Some(current), _) if *current < access => return Ok(()),
+            (Some(_), Access::Read) => { grants.remove(collection); }
+            (Some(_), Access::Write) => { grants.insert(collection.to_string(), Access::Read); }
+            (Some(_), Access::Admin) => { grants.insert(collection.to_string(), Access::Write); }
+            (None, _) => return Ok(()),
+        }
+        if grants.is_empty() {
+            self.grants.remove(grantee);
+        }
+        self.save(path)
+    }
+
+    pub fn create_session_token(&mut self, session: &Session) -> Result<String, DatabaseError> {
+        let now = AuthManager::now();
+        // expired tokens are only cleared out here, which is enough to keep them from piling up
+        self.session_tokens.retain(|_, (_, expires)| *expires > now);
+        let token = AuthManager::random_token()?;
+        self.session_tokens.insert(token.clone(), (session.clone(), now + SESSION_TOKEN_SECONDS));
+        Ok(token)
+    }
+
+    // Signs a client out before its token runs out, false if it wasn't one of these tokens
+    pub fn revoke_session_token(&mut self, token: &str) -> bool {
+        self.session_tokens.remove(token).is_some()
+    }
 
-        // might eventually move this
-        let encoded : Vec<u8> = bincode::serialize(&self)?;
+    // Either a token from create_session_token or an API token
+    pub fn session_from_token(&self, token: &str) -> Result<Session, DatabaseError> {
+        self.session_from_token_at(token, AuthManager::now())
+    }
+
+    fn session_from_token_at(&self, token: &str, now: u64) -> Result<Session, DatabaseError> {
+        match self.session_tokens.get(token) {
+            Some((session, expires)) if *expires > now => Ok(session.clone()),
+            Some(_) => Err(DatabaseError::UserError("Invalid token".to_string())),
+            None => self.api_token_session(token, now),
+        }
+    }
+
+    // Hands back the token itself, which is the only time it can be seen
+    pub fn create_api_token(&mut self, path: &str, owner: &str, lifetime: u64, read_only: bool) -> Result<(String, ApiToken), DatabaseError> {
+        if !self.users.contains_key(owner) {
+            return Err(DatabaseError::UserError(format!("{} not found", owner)))
+        }
+        let id = AuthManager::random_hex(8)?;
+        let secret = AuthManager::random_hex(32)?;
+        let created = AuthManager::now();
+        let token = ApiToken {
+            id: id.clone(),
+            owner: owner.to_string(),
+            secret_hash: AuthManager::sha256(&secret),
+            created,
+            expires: created.saturating_add(lifetime),
+            read_only,
+        };
+        self.api_tokens.insert(id.clone(), token.clone());
+        self.save(path)?;
+        Ok((format!("{}{}_{}", TOKEN_PREFIX, id, secret), token))
+    }
+
+    // Sorted by when they were made, expired ones included until they're revoked
+    pub fn api_tokens(&self) -> Vec<&ApiToken> {
+        let mut tokens: Vec<&ApiToken> = self.api_tokens.values().collect();
+        tokens.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+        tokens
+    }
+
+    pub fn revoke_api_token(&mut self, path: &str, id: &str) -> Result<ApiToken, DatabaseError> {
+        let token = self.api_tokens.remove(id).ok_or(DatabaseError::ValueNotFound(format!("token {}", id)))?;
+        self.save(path)?;
+        Ok(token)
+    }
+
+    fn api_token_session(&self, token: &str, now: u64) -> Result<Session, DatabaseError> {
+        let invalid = || DatabaseError::UserError("Invalid token".to_string());
+        let (id, secret) = token.strip_prefix(TOKEN_PREFIX).and_then(|token| token.split_once('_')).ok_or_else(invalid)?;
+        let api_token = self.api_tokens.get(id).ok_or_else(invalid)?;
+
+        // compared all the way through so the time taken doesn't say how much of it matched
+        let hash = AuthManager::sha256(secret);
+        let matches = hash.len() == api_token.secret_hash.len()
+            && hash.bytes().zip(api_token.secret_hash.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+        if !matches || api_token.expires <= now {
+            return Err(invalid())
+        }
+
+        let user = self.users.get(&api_token.owner).ok_or_else(invalid)?;
+        let mut session = AuthManager::create_session(user);
+        session.read_only = api_token.read_only;
+        Ok(session)
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open(format!("{}/users.log", path))?;
+    fn sha256(secret: &str) -> String {
+        Sha256::digest(secret.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
 
-        file.write_all(&encoded)?;
-        Ok(())
+    fn random_token() -> Result<String, DatabaseError> {
+        AuthManager::random_hex(32)
     }
 
-    fn verify_password(password: String, hash: &str) -> bool { 
+    fn random_hex(length: usize) -> Result<String, DatabaseError> {
+        let mut bytes = vec![0u8; length];
+        getrandom::fill(&mut bytes).map_err(|e| DatabaseError::Other(format!("getrandom: {}", e)))?;
+        Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    fn verify_password(password: &str, hash: &str) -> bool { 
         verify(password, hash).unwrap_or(false)
     }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use tempdir::TempDir;
+
+    use crate::auth::{Access, AuthManager, Permissions, LOGIN_FAILED, MAX_FAILED_ATTEMPTS, SESSION_TOKEN_SECONDS, USERS_MAGIC};
+    use crate::errors::DatabaseError;
+
+    #[test]
+    fn failed_logins_lock_the_account() {
+        let dir = TempDir::new("auth").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut auth = AuthManager::new(path, None).unwrap();
+        auth.new_user(path, &"alice".to_string(), &"password".to_string(), Permissions::User()).unwrap();
+
+        let login = |auth: &mut AuthManager, user: &str, password: &str, now: u64| auth.login_at(path, user.to_string(), password.to_string(), now);
+        let message = |result: Result<_, DatabaseError>| match result {
+            Err(DatabaseError::UserError(message)) => message,
+            other => panic!("expected a login error, got {:?}", other),
+        };
+        // nothing to tell a missing user from a wrong password
+        assert_eq!(message(login(&mut auth, "nobody", "password", 0)), LOGIN_FAILED);
+        assert_eq!(message(login(&mut auth, "alice", "wrong", 0)), LOGIN_FAILED);
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            assert!(login(&mut auth, "alice", "wrong", 0).is_err());
+        }
+        // locked, even with the right password, and the lock is in users.log
+        assert!(login(&mut auth, "alice", "password", 10).is_err());
+        let mut auth = AuthManager::load(path, None).unwrap();
+        assert!(login(&mut auth, "alice", "password", 10).is_err());
+
+        // once it runs out another failure locks it for twice as long
+        assert!(login(&mut auth, "alice", "wrong", 31).is_err());
+        assert!(login(&mut auth, "alice", "password", 31 + 59).is_err());
+        assert!(login(&mut auth, "alice", "password", 31 + 60).is_ok());
+        assert_eq!(auth.users()[0].failed_attempts, 0);
+
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            assert!(login(&mut auth, "alice", "wrong", 100).is_err());
+        }
+        auth.unlock(path, "alice").unwrap();
+        assert!(login(&mut auth, "alice", "password", 100).is_ok());
+
+        // the old password doesn't get in once it's been changed, even if it was checked before
+        let mut attempt = auth.begin_login("alice".to_string(), "password".to_string());
+        attempt.check();
+        auth.set_password(path, "alice", "changed").unwrap();
+        assert_eq!(message(auth.finish_login(path, attempt)), LOGIN_FAILED);
+        assert!(login(&mut auth, "alice", "changed", 100).is_ok());
+    }
+
+    #[test]
+    fn users_from_before_the_magic_are_upgraded() {
+        let dir = TempDir::new("auth").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/data/users.log"), dir.path().join("users.log")).unwrap();
+
+        let mut auth = AuthManager::load(path, None).unwrap();
+        assert!(!auth.users().is_empty());
+        assert!(auth.users().iter().all(|user| user.failed_attempts == 0));
+        auth.new_user(path, &"new".to_string(), &"password".to_string(), Permissions::Guest()).unwrap();
+        assert!(fs::read(dir.path().join("users.log")).unwrap().starts_with(USERS_MAGIC));
+        assert!(AuthManager::load(path, None).unwrap().users().iter().any(|user| user.username == "new"));
+    }
+
+    #[test]
+    fn api_tokens_stand_in_for_passwords() {
+        let dir = TempDir::new("auth").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut auth = AuthManager::new(path, None).unwrap();
+        auth.new_user(path, &"ci".to_string(), &"password".to_string(), Permissions::User()).unwrap();
+
+        let (token, stored) = auth.create_api_token(path, "ci", 60, true).unwrap();
+        assert!(!format!("{:?}", auth.api_tokens()).contains(token.rsplit('_').next().unwrap()));
+
+        // through users.log, as a password and as a bearer token
+        let mut auth = AuthManager::load(path, None).unwrap();
+        let session = auth.login(path, "ci".to_string(), token.clone()).unwrap();
+        assert!(auth.authorize(&session, None, Access::Read).is_ok());
+        assert!(auth.authorize(&session, None, Access::Write).is_err());
+        assert_eq!(auth.session_from_token(&token).unwrap().user, "ci");
+        assert!(auth.login(path, "someone".to_string(), token.clone()).is_err());
+        assert!(auth.session_from_token(&format!("{}0", token)).is_err());
+        assert!(auth.api_token_session(&token, stored.expires).is_err());
+
+        auth.revoke_api_token(path, &stored.id).unwrap();
+        assert!(auth.session_from_token(&token).is_err());
+
+        // a token from logging in runs out, or can be ended before then
+        let bearer = auth.create_session_token(&session).unwrap();
+        assert!(auth.session_from_token_at(&bearer, AuthManager::now() + SESSION_TOKEN_SECONDS).is_err());
+        assert!(auth.revoke_session_token(&bearer));
+        assert!(auth.session_from_token(&bearer).is_err());
+        assert!(!auth.revoke_session_token(&bearer));
+    }
+}
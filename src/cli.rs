@@ -1,65 +1,389 @@
-use clap::Parser;
-use std::io::{self, Write};
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::io::{self, BufRead, Write};
 
 
-use crate::parser::Parser as ReplParser;
-use crate::database::Database;
-use crate::database::Response;
+use database::Parser as ReplParser;
+use database::{Database, DatabaseError, KeySource, Output, Permissions, Printer, RecoveryPolicy};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct CLI {
+    #[arg(short, long, required = true)]
+    pub username: Option<String>,
+
+    /// best left out as it shows up in shell history and ps, without it the password comes from
+    /// --password-file, then DATABASE_PASSWORD, then a prompt
     #[arg(short, long)]
-    username: String,
+    pub password: Option<String>,
+
+    /// file holding the password, for scripts
+    #[arg(long, conflicts_with = "password")]
+    pub password_file: Option<String>,
+
+    #[arg(short, long, default_value="./data", global = true)]
+    pub dir: String,
+
+    /// create the user given by --username and --password, authorized by the admin in --admin
+    #[arg(short, long, default_value_t=false, requires = "admin")]
+    pub new_user: bool,
+
+    /// admin that authorizes --new-user, their password comes from DATABASE_ADMIN_PASSWORD or a prompt
+    #[arg(long, requires = "new_user")]
+    pub admin: Option<String>,
+
+    /// role for the user made by --new-user: admin, user, guest or restricted
+    #[arg(long, default_value="user")]
+    pub role: Permissions,
 
+    /// what to do with a collection file that can't be read: fail, quarantine or read-only
+    #[arg(long, default_value="fail", global = true)]
+    pub on_corruption: RecoveryPolicy,
+
+    /// file holding the key of an encrypted database as 64 hex characters
+    #[arg(long, global = true)]
+    pub key_file: Option<String>,
+
+    /// file holding the passphrase of an encrypted database, without it the passphrase comes from
+    /// DATABASE_PASSPHRASE, then a prompt
+    #[arg(long, global = true, conflicts_with = "key_file")]
+    pub passphrase_file: Option<String>,
+
+    /// run this command instead of starting the REPL, can be given more than once
+    #[arg(short, long, conflicts_with = "file")]
+    pub execute: Vec<String>,
+
+    /// run the commands in this file instead of starting the REPL
     #[arg(short, long)]
-    password: String,
+    pub file: Option<String>,
 
-    #[arg(short, long, default_value="./data")]
-    dir: String,
+    /// stop at the first command that fails in --execute, --file or piped input
+    #[arg(long, default_value_t=false)]
+    pub stop_on_error: bool,
 
-    #[arg(short, long, default_value_t=false)]
-    new_user: bool,
+    /// how results are printed: json, ndjson, table or pretty. Errors go to stderr, and the
+    /// default is pretty in the REPL and ndjson otherwise
+    #[arg(short, long)]
+    pub output: Option<Output>,
 
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// create a new database with an admin, named by --admin, DATABASE_ADMIN_USER or a prompt and
+    /// with a password from DATABASE_ADMIN_PASSWORD or a prompt
+    Init {
+        #[arg(long)]
+        admin: Option<String>,
+
+        /// encrypt it with --key-file or a new passphrase from --passphrase-file, DATABASE_PASSPHRASE
+        /// or a prompt
+        #[arg(long, default_value_t=false)]
+        encrypt: bool,
+    },
+    /// re-encrypt the database under a new key, or encrypt one that isn't yet. The new passphrase
+    /// comes from DATABASE_NEW_PASSPHRASE or a prompt when neither file is given
+    Rekey {
+        #[arg(long)]
+        new_key_file: Option<String>,
+
+        #[arg(long, conflicts_with = "new_key_file")]
+        new_passphrase_file: Option<String>,
+
+        /// take the encryption off instead
+        #[arg(long, conflicts_with_all = ["new_key_file", "new_passphrase_file"])]
+        decrypt: bool,
+    },
+    /// accept the same commands as the REPL over TCP, one per line
+    Serve {
+        #[arg(short, long, default_value="127.0.0.1:7878")]
+        address: String,
+    },
+    /// serve collections and keys as JSON over HTTP
+    Http {
+        #[arg(short, long, default_value="127.0.0.1:8080")]
+        address: String,
+    },
 }
 
 
 impl CLI {
-    pub fn get_args() -> (String, String, String, bool) {
-        let args = CLI::parse();
-        (args.username, args.password, args.dir, args.new_user)
+    pub fn get_args() -> CLI {
+        CLI::parse()
+    }
+
+    // Where the password comes from when it isn't given with -p
+    pub fn password(&self, username: &str) -> Result<String, String> {
+        if let Some(password) = &self.password {
+            return Ok(password.clone())
+        }
+        if let Some(file) = &self.password_file {
+            return CLI::read_secret(file)
+        }
+        if let Ok(password) = std::env::var("DATABASE_PASSWORD") {
+            return Ok(password)
+        }
+        CLI::prompt_password(&format!("Password for {}", username))
+            .map_err(|e| format!("Couldn't prompt for a password ({}), use --password-file or DATABASE_PASSWORD instead", e))
+    }
+
+    // Doesn't echo what's typed, and fails when there's no terminal to ask on
+    pub fn prompt_password(label: &str) -> io::Result<String> {
+        rpassword::prompt_password(format!("{}: ", label))
+    }
+
+    pub fn prompt(label: &str) -> io::Result<String> {
+        print!("{}: ", label);
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        Ok(input.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    // The environment variable if it's set, otherwise whatever gets typed at the prompt
+    pub fn env_or_prompt(var: &str, label: &str) -> io::Result<String> {
+        match std::env::var(var) {
+            Ok(value) => Ok(value),
+            Err(_) => CLI::prompt(label),
+        }
+    }
+
+    // Asks for the password twice when it has to prompt so a typo doesn't lock the admin out
+    pub fn new_admin(username: Option<String>) -> Result<(String, String), String> {
+        let username = match username {
+            Some(username) => username,
+            None => CLI::env_or_prompt("DATABASE_ADMIN_USER", "Admin username").map_err(|e| e.to_string())?,
+        };
+        let password = match std::env::var("DATABASE_ADMIN_PASSWORD") {
+            Ok(password) => password,
+            Err(_) => {
+                let password = CLI::prompt_password("Admin password").map_err(|e| e.to_string())?;
+                if CLI::prompt_password("Repeat password").map_err(|e| e.to_string())? != password {
+                    return Err("Passwords don't match".to_string())
+                }
+                password
+            }
+        };
+        if username.is_empty() || password.is_empty() {
+            return Err("Admin username and password can't be empty".to_string())
+        }
+        Ok((username, password))
+    }
+
+    // The key for the database in dir, only asking for a passphrase when it was encrypted with one
+    pub fn key(&self, dir: &str) -> Result<Option<KeySource>, String> {
+        if let Some(file) = &self.key_file {
+            return Ok(Some(KeySource::KeyFile(file.clone())))
+        }
+        if let Some(file) = &self.passphrase_file {
+            return CLI::read_secret(file).map(|passphrase| Some(KeySource::Passphrase(passphrase)))
+        }
+        if !database::crypto::needs_passphrase(dir) {
+            return Ok(None)
+        }
+        let passphrase = match std::env::var("DATABASE_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => CLI::prompt_password(&format!("Passphrase for {}", dir)).map_err(|e| e.to_string())?,
+        };
+        Ok(Some(KeySource::Passphrase(passphrase)))
+    }
+
+    // A key that's about to be used for the first time, passphrases typed in are asked for twice
+    pub fn new_key(key_file: Option<String>, passphrase_file: Option<String>, var: &str) -> Result<KeySource, String> {
+        if let Some(file) = key_file {
+            return Ok(KeySource::KeyFile(file))
+        }
+        let passphrase = match (passphrase_file, std::env::var(var)) {
+            (Some(file), _) => CLI::read_secret(&file)?,
+            (None, Ok(passphrase)) => passphrase,
+            (None, Err(_)) => {
+                let passphrase = CLI::prompt_password("New passphrase").map_err(|e| e.to_string())?;
+                if CLI::prompt_password("Repeat passphrase").map_err(|e| e.to_string())? != passphrase {
+                    return Err("Passphrases don't match".to_string())
+                }
+                passphrase
+            }
+        };
+        if passphrase.is_empty() {
+            return Err("Passphrase can't be empty".to_string())
+        }
+        Ok(KeySource::Passphrase(passphrase))
+    }
+
+    fn read_secret(file: &str) -> Result<String, String> {
+        fs::read_to_string(file)
+            .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| format!("{}: {}", file, e))
     }
 
-    pub fn start_repl(mut database : Database, parser: ReplParser) -> () {
+    // A line and however many more it takes to finish the command, None once the input runs out
+    pub fn read_command(reader: &mut impl BufRead, parser: &ReplParser, prompt: Option<&str>) -> io::Result<Option<String>> {
         let mut input = String::new();
+        if let Some(prompt) = prompt {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+        }
+        if reader.read_line(&mut input)? == 0 {
+            return Ok(None)
+        }
+        // JSON or a quoted key that runs onto more lines
+        while parser.is_unfinished(&input) {
+            if prompt.is_some() {
+                print!("         ... ");
+                io::stdout().flush()?;
+            }
+            if reader.read_line(&mut input)? == 0 {
+                break
+            }
+        }
+        Ok(Some(input))
+    }
+
+    pub fn start_repl(mut database : Database, parser: ReplParser, output: Output) {
+        let mut stdin = io::stdin().lock();
+        let mut printer = Printer::new(output, io::stdout());
         loop {
-            print!("Database > ");
-            io::stdout().flush().unwrap();
-            input.clear();
-            io::stdin().read_line(&mut input).unwrap();
+            let input = match CLI::read_command(&mut stdin, &parser, Some("Database > ")) {
+                Ok(Some(input)) => input,
+                Ok(None) => {
+                    eprintln!();
+                    break
+                }
+                Err(e) => {
+                    eprintln!("{}", output.render_error(&e.into()));
+                    break
+                }
+            };
 
             if input.trim().to_uppercase().eq("EXIT") || input.trim().to_uppercase().eq("QUIT") {
                 break
             }
 
-            let command = parser.get_command(&input.trim());
-            match command { 
-                Ok(command) => {
-                    let result = database.operate_db(command);
-                    match result {
-                        Ok(Response::Value(serde_json::Value::Null)) => (),
-                        Ok(Response::Value(result)) => println!("{}", result),
-                        Ok(Response::Message(message)) => println!("{}", message),
-                        Err(e) => println!("{}", e),
-                    }
-                    }
-                Err(e) => println!("{}", e),
+            match parser.get_command(input.trim()).and_then(|command| database.operate_db(command)) {
+                Ok(response) => if let Err(e) = printer.print(&response) {
+                    eprintln!("{}", output.render_error(&e.into()));
+                },
+                Err(e) => eprintln!("{}", output.render_error(&e)),
             }
         }
-        println!("Saving");
-        database.save_data().unwrap();
+        if let Err(e) = printer.finish() {
+            eprintln!("{}", output.render_error(&e.into()));
+        }
+        if let Err(e) = CLI::save(&mut database) {
+            eprintln!("{}", output.render_error(&e));
+        }
+
+    }
+
+    // Everything is in the WAL already, so this only keeps it short. A session that changed
+    // nothing has nothing to write, which lets read-only users finish without an error
+    fn save(database: &mut Database) -> Result<(), DatabaseError> {
+        match database.has_unsaved_changes() {
+            true => database.checkpoint(),
+            false => Ok(()),
+        }
+    }
+
+    // run_batch and then saving, giving the exit status: 0 when every command worked and 1 when
+    // one failed or what they changed couldn't be saved
+    pub fn batch(database: &mut Database, parser: &ReplParser, next: impl FnMut() -> io::Result<Option<String>>, stop_on_error: bool, output: Output) -> u8 {
+        let succeeded = CLI::run_batch(database, parser, next, stop_on_error, output);
+        if let Err(e) = CLI::save(database) {
+            eprintln!("{}", output.render_error(&e));
+            return 1
+        }
+        match succeeded {
+            true => 0,
+            false => 1,
+        }
+    }
 
+    // Runs commands without prompting, printing each result the way output says. Blank lines and
+    // lines starting with -- are skipped. False if any command failed
+    pub fn run_batch(database: &mut Database, parser: &ReplParser, mut next: impl FnMut() -> io::Result<Option<String>>, stop_on_error: bool, output: Output) -> bool {
+        let mut printer = Printer::new(output, io::stdout());
+        let mut succeeded = true;
+        loop {
+            let input = match next() {
+                Ok(Some(input)) => input,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}", output.render_error(&DatabaseError::from(e)));
+                    succeeded = false;
+                    break
+                }
+            };
+            let input = input.trim();
+            if input.is_empty() || input.starts_with("--") {
+                continue
+            }
+            if input.eq_ignore_ascii_case("EXIT") || input.eq_ignore_ascii_case("QUIT") {
+                break
+            }
+
+            match parser.get_command(input).and_then(|command| database.operate_db(command)) {
+                Ok(response) => if let Err(e) = printer.print(&response) {
+                    eprintln!("{}", output.render_error(&e.into()));
+                    succeeded = false;
+                },
+                Err(e) => {
+                    eprintln!("{}", output.render_error(&e));
+                    succeeded = false;
+                    if stop_on_error {
+                        break
+                    }
+                }
+            }
+        }
+        if let Err(e) = printer.finish() {
+            eprintln!("{}", output.render_error(&e.into()));
+            succeeded = false;
+        }
+        succeeded
     }
     
 }
 
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+    use serde_json::{json, Value};
+    use tempdir::TempDir;
+
+    use database::{Database, Output, Parser, Permissions};
+    use crate::cli::CLI;
+
+    #[test]
+    fn batches_save_and_give_an_exit_status() {
+        let dir = TempDir::new("cli").unwrap();
+        let mut database = Database::init(dir.path().to_str().unwrap().to_string(), &"admin".to_string(), &"password".to_string()).unwrap();
+        database.login("admin".to_string(), "password".to_string()).unwrap();
+        let parser = Parser::new();
+        let run = |database: &mut Database, script: &str, stop_on_error: bool| {
+            let mut reader = Cursor::new(script.to_string());
+            CLI::batch(database, &parser, || CLI::read_command(&mut reader, &parser, None), stop_on_error, Output::Ndjson)
+        };
+
+        // comments and blank lines are skipped and a value can run over several lines
+        assert_eq!(run(&mut database, "-- seed the orders\nNEW orders\n\nSELECT orders\nINSERT \"order 1\" {\n  \"total\": 5\n}\n", false), 0);
+        assert_eq!(database.collection("orders").unwrap().get::<Value>("order 1").unwrap(), Some(json!({"total": 5})));
+        assert!(!database.has_unsaved_changes());
+
+        // a failure sets the status, and stops the rest only when asked to
+        assert_eq!(run(&mut database, "GET missing\nINSERT a 1\n", true), 1);
+        assert_eq!(database.collection("orders").unwrap().get::<Value>("a").unwrap(), None);
+        assert_eq!(run(&mut database, "GET missing\nINSERT a 1\n", false), 1);
+        assert_eq!(database.collection("orders").unwrap().get::<Value>("a").unwrap(), Some(json!(1)));
+
+        // a user who can only read gets through a batch that only reads
+        let admin = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.create_user(&admin, "reader".to_string(), "password".to_string(), Permissions::Guest()).unwrap();
+        database.login("reader".to_string(), "password".to_string()).unwrap();
+        assert_eq!(run(&mut database, "SELECT orders\nGET a\n", true), 0);
+        assert_eq!(run(&mut database, "SELECT orders\nINSERT b 2\n", true), 1);
+    }
+}
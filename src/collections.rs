@@ -1,9 +1,16 @@
 use serde::{Serialize, Deserialize};
-use serde_json::{Map, Value};
+use serde_json::Value;
 use serde::{Serializer, Deserializer};
 use serde::ser::SerializeMap;
 use serde::de::{self, Visitor, MapAccess};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
+use std::ops::Bound;
+
+use crate::schema::Schema;
+
+// Keys grouped by the JSON value found at an index's path, values are compared as their JSON text
+type Index = BTreeMap<String, BTreeSet<String>>;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Collection { 
@@ -11,16 +18,32 @@ pub struct Collection {
         serialize_with = "map_value_to_string",
         deserialize_with = "map_string_to_value"
     )]
-    data: Map<String, Value>,
+    // kept sorted by key so prefix and range scans only walk the keys they return
+    data: BTreeMap<String, Value>,
     pub name: String,
+    // only the paths are written with the snapshot, the entries are rebuilt from the data
+    #[serde(skip)]
+    indexes: HashMap<String, Index>,
+    // written with the snapshot after the index paths, the same way
+    #[serde(skip)]
+    schema: Option<Schema>,
 }
 
 impl Collection {
     pub fn new(name: String) -> Collection {
-        Collection{ data: Map::new(), name }
+        Collection{ data: BTreeMap::new(), name, indexes: HashMap::new(), schema: None }
     }
     pub fn insert(&mut self, key : String, value: Value) -> Option<Value> {
-        self.data.insert(key, value)
+        for (path, index) in self.indexes.iter_mut() {
+            if let Some(field) = lookup(&value, path) {
+                index.entry(field.to_string()).or_default().insert(key.clone());
+            }
+        }
+        let previous = self.data.insert(key.clone(), value);
+        if let Some(previous) = &previous {
+            self.unindex(&key, previous);
+        }
+        previous
     }
 
     pub fn get(&self, key : String) -> Option<Value> {
@@ -28,17 +51,114 @@ impl Collection {
     }
 
     pub fn delete(&mut self, key: String) -> Option<Value> {
-        self.data.remove(&key)
+        let previous = self.data.remove(&key);
+        if let Some(previous) = &previous {
+            self.unindex(&key, previous);
+        }
+        previous
     }
+
+    // Drops the index entries for a value that is no longer stored under the key
+    fn unindex(&mut self, key: &String, previous: &Value) {
+        let current = self.data.get(key);
+        for (path, index) in self.indexes.iter_mut() {
+            let Some(field) = lookup(previous, path) else { continue };
+            // the new value may still have the same field
+            if current.and_then(|current| lookup(current, path)) == Some(field) {
+                continue
+            }
+            let field = field.to_string();
+            if let Some(keys) = index.get_mut(&field) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    index.remove(&field);
+                }
+            }
+        }
+    }
+
+    // Returns false if there already was an index on that path
+    pub fn create_index(&mut self, path: String) -> bool {
+        if self.indexes.contains_key(&path) {
+            return false
+        }
+        let mut index = Index::new();
+        for (key, value) in &self.data {
+            if let Some(field) = lookup(value, &path) {
+                index.entry(field.to_string()).or_default().insert(key.clone());
+            }
+        }
+        self.indexes.insert(path, index);
+        true
+    }
+
+    pub fn drop_index(&mut self, path: &str) -> bool {
+        self.indexes.remove(path).is_some()
+    }
+
+    pub fn index_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.indexes.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    pub fn schema(&self) -> Option<&Schema> {
+        self.schema.as_ref()
+    }
+
+    pub fn set_schema(&mut self, schema: Option<Schema>) {
+        self.schema = schema;
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn documents(&self) -> Vec<(String, Value)> {
+        self.data.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+    }
+
+    // Keys between the bounds in order, nothing if the bounds are the wrong way round
+    pub fn range<'a>(&'a self, from: Bound<&'a str>, to: Bound<&'a str>) -> impl Iterator<Item = (&'a String, &'a Value)> + 'a {
+        let empty = match (from, to) {
+            (Bound::Included(from), Bound::Included(to)) => from > to,
+            (Bound::Included(from) | Bound::Excluded(from), Bound::Included(to) | Bound::Excluded(to)) => from >= to,
+            _ => false,
+        };
+        (!empty).then(|| self.data.range::<str, _>((from, to))).into_iter().flatten()
+    }
+
+    pub fn scan<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a Value)> + 'a {
+        self.range(Bound::Included(prefix), Bound::Unbounded)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    // Every key whose value has the given value at the path, through the index when there is one
+    pub fn find(&self, path: &str, value: &Value) -> Vec<(String, Value)> {
+        match self.indexes.get(path) {
+            Some(index) => index.get(&value.to_string())
+                .map(|keys| keys.iter().map(|key| (key.clone(), self.data[key].clone())).collect())
+                .unwrap_or_default(),
+            None => self.data.iter()
+                .filter(|(_, document)| lookup(document, path) == Some(value))
+                .map(|(key, document)| (key.clone(), document.clone()))
+                .collect(),
+        }
+    }
+}
+
+// Follows a dotted path like address.city into a document
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, field| value.get(field))
 }
 
 // This mess is because bincode cannot work with Json Values (YAY) so it must be converted into
 // string values when being stores on the disk, when deserializing it converts back to being a Json
 // Value 
 
-// Serialize: Map<String, Value> → BTreeMap<String, String>
+// Serialize: BTreeMap<String, Value> → BTreeMap<String, String>
 fn map_value_to_string<S>(
-    map: &Map<String, Value>,
+    map: &BTreeMap<String, Value>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
@@ -51,17 +171,17 @@ where
     ser_map.end()
 }
 
-// Deserialize: BTreeMap<String, String> → Map<String, Value>
+// Deserialize: BTreeMap<String, String> → BTreeMap<String, Value>
 fn map_string_to_value<'de, D>(
     deserializer: D,
-) -> Result<Map<String, Value>, D::Error>
+) -> Result<BTreeMap<String, Value>, D::Error>
 where
     D: Deserializer<'de>,
 {
     struct StringToValueVisitor;
 
     impl<'de> Visitor<'de> for StringToValueVisitor {
-        type Value = Map<String, Value>;
+        type Value = BTreeMap<String, Value>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
             formatter.write_str("a map of stringified JSON values")
@@ -71,7 +191,7 @@ where
         where
             M: MapAccess<'de>,
         {
-            let mut map = Map::new();
+            let mut map = BTreeMap::new();
             while let Some((k, v)) = access.next_entry::<String, String>()? {
                 let val: Value = serde_json::from_str(&v)
                     .map_err(de::Error::custom)?;
@@ -84,3 +204,48 @@ where
     deserializer.deserialize_map(StringToValueVisitor)
 }
 
+
+#[cfg(test)]
+mod tests {
+
+    use std::ops::Bound;
+    use serde_json::json;
+
+    use crate::collections::Collection;
+
+    #[test]
+    fn index_follows_inserts_and_deletes() {
+        let mut collection = Collection::new("users".to_string());
+        collection.insert("a".to_string(), json!({"email": "a@x", "address": {"city": "Oslo"}}));
+        collection.create_index("address.city".to_string());
+        collection.insert("b".to_string(), json!({"email": "b@x", "address": {"city": "Oslo"}}));
+        collection.insert("a".to_string(), json!({"email": "a@x", "address": {"city": "Rome"}}));
+
+        let keys = |found: Vec<(String, serde_json::Value)>| found.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(collection.find("address.city", &json!("Oslo"))), vec!["b"]);
+        assert_eq!(keys(collection.find("address.city", &json!("Rome"))), vec!["a"]);
+
+        collection.delete("a".to_string());
+        assert!(collection.find("address.city", &json!("Rome")).is_empty());
+        // unindexed paths fall back to a scan
+        assert_eq!(keys(collection.find("email", &json!("b@x"))), vec!["b"]);
+    }
+
+    #[test]
+    fn keys_scan_in_order() {
+        let mut collection = Collection::new("orders".to_string());
+        for key in ["order:2024:0002", "order:2023:0001", "order:2024:0001", "order:2025:0001", "user:1"] {
+            collection.insert(key.to_string(), json!(key));
+        }
+        let keys = |found: Vec<(&String, &serde_json::Value)>| found.into_iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+        assert_eq!(keys(collection.scan("order:2024:").collect()), vec!["order:2024:0001", "order:2024:0002"]);
+        assert_eq!(keys(collection.range(Bound::Included("order:2024"), Bound::Excluded("order:2025")).collect()),
+            vec!["order:2024:0001", "order:2024:0002"]);
+        assert_eq!(keys(collection.range(Bound::Excluded("order:2024:0002"), Bound::Unbounded).collect()),
+            vec!["order:2025:0001", "user:1"]);
+        // backwards or empty ranges find nothing instead of panicking
+        assert!(collection.range(Bound::Included("z"), Bound::Excluded("a")).next().is_none());
+        assert!(collection.range(Bound::Excluded("a"), Bound::Excluded("a")).next().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{Serialize, Deserialize};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use std::{
+    fmt,
+    fs,
+};
+
+use crate::audit::AuditLog;
+use crate::errors::DatabaseError;
+use crate::storage;
+
+// The encryption file says how the key is found and holds a sealed known value, so a wrong key is
+// caught when the database is opened rather than showing up as every file being corrupt
+const KEY_MAGIC: &[u8; 8] = b"DBKEYS01";
+// Encrypted .db and users.log files start with this, then the nonce and the ciphertext
+const FILE_MAGIC: &[u8; 8] = b"DBCRYPT1";
+const KEY_CHECK: &[u8] = b"database key check";
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+// Where the key comes from when the database is opened, created or rekeyed
+#[derive(Clone)]
+pub enum KeySource {
+    // stretched with argon2id and the salt from the encryption file
+    Passphrase(String),
+    // path to a file holding the 32 byte key as 64 hex characters
+    KeyFile(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum KeyKind {
+    Passphrase { salt: [u8; SALT_LEN] },
+    KeyFile,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeyHeader {
+    kind: KeyKind,
+    check: Vec<u8>,
+}
+
+// XChaCha20-Poly1305, the nonces are random and long enough that reusing one isn't a worry
+#[derive(Clone)]
+pub struct Cipher {
+    key: Key,
+}
+
+// Neither shows up in logs or panics
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Passphrase(_) => write!(f, "Passphrase(..)"),
+            KeySource::KeyFile(path) => write!(f, "KeyFile({})", path),
+        }
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cipher(..)")
+    }
+}
+
+impl Cipher {
+    // The nonce followed by the ciphertext, aad has to be given again to open it
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(|e| DatabaseError::Other(format!("getrandom: {}", e)))?;
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .map_err(|_| DatabaseError::EncryptionError("couldn't encrypt".to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // None if it was sealed with another key or has been tampered with
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        XChaCha20Poly1305::new(&self.key)
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .ok()
+    }
+}
+
+fn header_path(path: &str) -> String {
+    format!("{}/encryption", path)
+}
+
+// So a front end knows to ask for a passphrase rather than expect a key file
+pub fn needs_passphrase(path: &str) -> bool {
+    fs::read(header_path(path)).ok()
+        .and_then(|contents| bincode::deserialize::<KeyHeader>(contents.strip_prefix(KEY_MAGIC.as_slice())?).ok())
+        .is_some_and(|header| matches!(header.kind, KeyKind::Passphrase { .. }))
+}
+
+// The cipher for the database in that directory, None if it isn't encrypted. A key that doesn't
+// match, or a key for a database that isn't encrypted, is an error
+pub fn load(path: &str, source: Option<&KeySource>) -> Result<Option<Cipher>, DatabaseError> {
+    let contents = match fs::read(header_path(path)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return match source {
+                Some(_) => Err(DatabaseError::EncryptionError(format!("{} isn't encrypted, open it without a key", path))),
+                None => Ok(None),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let header: KeyHeader = contents.strip_prefix(KEY_MAGIC.as_slice())
+        .and_then(|body| bincode::deserialize(body).ok())
+        .ok_or_else(|| DatabaseError::CorruptData { file: header_path(path), offset: 0, reason: "not an encryption header".to_string() })?;
+
+    let cipher = match (&header.kind, source) {
+        (_, None) => return Err(DatabaseError::EncryptionError(format!("{} is encrypted, give its key file or passphrase", path))),
+        (KeyKind::Passphrase { salt }, Some(KeySource::Passphrase(passphrase))) => derive(passphrase, salt)?,
+        (KeyKind::KeyFile, Some(KeySource::KeyFile(file))) => read_key_file(file)?,
+        (KeyKind::Passphrase { .. }, Some(_)) => return Err(DatabaseError::EncryptionError(format!("{} is encrypted with a passphrase, not a key file", path))),
+        (KeyKind::KeyFile, Some(_)) => return Err(DatabaseError::EncryptionError(format!("{} is encrypted with a key file, not a passphrase", path))),
+    };
+    if cipher.open(&header.check, KEY_MAGIC).as_deref() != Some(KEY_CHECK) {
+        return Err(DatabaseError::EncryptionError(format!("Wrong key for {}", path)))
+    }
+    Ok(Some(cipher))
+}
+
+// A new cipher and the encryption file that goes with it, nothing is written yet
+pub fn create(source: Option<&KeySource>) -> Result<Option<(Cipher, Vec<u8>)>, DatabaseError> {
+    let (kind, cipher) = match source {
+        None => return Ok(None),
+        Some(KeySource::Passphrase(passphrase)) => {
+            let mut salt = [0u8; SALT_LEN];
+            getrandom::fill(&mut salt).map_err(|e| DatabaseError::Other(format!("getrandom: {}", e)))?;
+            (KeyKind::Passphrase { salt }, derive(passphrase, &salt)?)
+        }
+        Some(KeySource::KeyFile(file)) => (KeyKind::KeyFile, read_key_file(file)?),
+    };
+    let header = KeyHeader { kind, check: cipher.seal(KEY_CHECK, KEY_MAGIC)? };
+    let mut encoded = KEY_MAGIC.to_vec();
+    bincode::serialize_into(&mut encoded, &header)?;
+    Ok(Some((cipher, encoded)))
+}
+
+// Writes or removes the encryption file, whichever makes the database use the new key
+pub fn save_header(path: &str, header: Option<&[u8]>) -> Result<(), DatabaseError> {
+    match header {
+        Some(header) => storage::write_atomic(header_path(path), header),
+        None => match fs::remove_file(header_path(path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<Cipher, DatabaseError> {
+    let mut key = Key::default();
+    argon2::Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| DatabaseError::EncryptionError(format!("argon2: {}", e)))?;
+    Ok(Cipher { key })
+}
+
+fn read_key_file(file: &str) -> Result<Cipher, DatabaseError> {
+    let contents = fs::read_to_string(file).map_err(|e| DatabaseError::EncryptionError(format!("{}: {}", file, e)))?;
+    let hex = contents.trim();
+    let invalid = || DatabaseError::EncryptionError(format!("{} should hold a 32 byte key as 64 hex characters", file));
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid())
+    }
+    let mut key = Key::default();
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).map_err(|_| invalid())?;
+    }
+    Ok(Cipher { key })
+}
+
+// What's written to a .db or users.log file, left as it is when the database isn't encrypted
+pub fn seal_file(cipher: Option<&Cipher>, contents: Vec<u8>) -> Result<Vec<u8>, DatabaseError> {
+    match cipher {
+        Some(cipher) => {
+            let mut sealed = FILE_MAGIC.to_vec();
+            sealed.extend(cipher.seal(&contents, FILE_MAGIC)?);
+            Ok(sealed)
+        }
+        None => Ok(contents),
+    }
+}
+
+// The other way round. An encrypted database won't read a plain file in place of one of its own
+pub fn open_file(cipher: Option<&Cipher>, file: &str, contents: Vec<u8>) -> Result<Vec<u8>, DatabaseError> {
+    let corrupt = |reason: &str| DatabaseError::CorruptData { file: file.to_string(), offset: 0, reason: reason.to_string() };
+    match (cipher, contents.strip_prefix(FILE_MAGIC.as_slice())) {
+        (Some(cipher), Some(sealed)) => cipher.open(sealed, FILE_MAGIC).ok_or_else(|| corrupt("failed to decrypt")),
+        (Some(_), None) => Err(corrupt("not encrypted")),
+        (None, Some(_)) => Err(DatabaseError::EncryptionError(format!("{} is encrypted", file))),
+        (None, None) => Ok(contents),
+    }
+}
+
+// Rekeying writes every file under the new key to <file>.rekey before the encryption file is
+// switched over, and renames them into place after. Whatever a crash left behind is finished off
+// if the new key made it into the encryption file and thrown away if it didn't
+pub fn finish_rekey(path: &str, cipher: Option<&Cipher>) -> Result<(), DatabaseError> {
+    for entry in fs::read_dir(path)? {
+        let staged = entry?.path();
+        if !staged.is_file() || staged.extension() != Some("rekey".as_ref()) {
+            continue
+        }
+        let contents = fs::read(&staged)?;
+        // audit.log is sealed a line at a time rather than as a whole
+        let current = match staged.file_stem() == Some("audit.log".as_ref()) {
+            true => AuditLog::readable(&contents, cipher),
+            false => open_file(cipher, staged.to_str().unwrap(), contents).is_ok(),
+        };
+        match current {
+            true => fs::rename(&staged, staged.with_extension(""))?,
+            false => fs::remove_file(&staged)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use tempdir::TempDir;
+
+    use crate::crypto::{self, KeySource};
+    use crate::errors::DatabaseError;
+
+    #[test]
+    fn wrong_key_is_refused() {
+        let dir = TempDir::new("crypto").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let source = KeySource::Passphrase("correct horse".to_string());
+        let (cipher, header) = crypto::create(Some(&source)).unwrap().unwrap();
+        crypto::save_header(path, Some(&header)).unwrap();
+
+        let sealed = crypto::seal_file(Some(&cipher), b"personal data".to_vec()).unwrap();
+        assert!(!sealed.windows(8).any(|window| window == b"personal"));
+        let cipher = crypto::load(path, Some(&source)).unwrap().unwrap();
+        assert_eq!(crypto::open_file(Some(&cipher), "users.log", sealed.clone()).unwrap(), b"personal data");
+
+        assert!(matches!(crypto::load(path, Some(&KeySource::Passphrase("wrong".to_string()))), Err(DatabaseError::EncryptionError(_))));
+        assert!(matches!(crypto::load(path, None), Err(DatabaseError::EncryptionError(_))));
+        assert!(matches!(crypto::open_file(None, "users.log", sealed), Err(DatabaseError::EncryptionError(_))));
+
+        // a key file for a passphrase database, and a key that's too short
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, "ab".repeat(32)).unwrap();
+        let key_file = key_file.to_str().unwrap().to_string();
+        assert!(crypto::load(path, Some(&KeySource::KeyFile(key_file.clone()))).is_err());
+        fs::write(&key_file, "ab".repeat(31)).unwrap();
+        assert!(crypto::create(Some(&KeySource::KeyFile(key_file))).is_err());
+    }
+}
@@ -1,28 +1,80 @@
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 
 use std::{
+    ops::Bound,
     option::Option,
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
-    io::{Read, Write}
+    io::Read,
 };
 
-use bincode;
 use serde_json::Value;
 
 use crate::wal::WALManager;
 use crate::wal::WALEntry;
-use crate::parser::Command;
+use crate::parser::{Command, Key};
+use crate::query::Query;
 use crate::collections::Collection;
-use crate::auth::{Permissions, AuthManager};
+use crate::auth::{Access, ApiToken, AuthManager, Grantee, LoginAttempt, Permissions};
 use crate::session::Session;
 use crate::errors::DatabaseError;
+use crate::storage::{self, Manifest, SnapshotHeader};
+use crate::crypto::{self, Cipher, KeySource};
+use crate::audit::{AuditEntry, AuditFilter, AuditLog};
+use crate::schema::Schema;
+use crate::update::Update;
 
-#[derive(Serialize, Deserialize, Debug)]
-enum DatabaseState {
-    SelectedCollection(usize),
+// how many keys SCAN and RANGE return when no LIMIT is given
+const PAGE_SIZE: usize = 100;
+// how long CREATE TOKEN's tokens last without EXPIRES
+const TOKEN_LIFETIME: u64 = 30 * 24 * 60 * 60;
+// longest collection name NEW and RENAME COLLECTION accept
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DatabaseState {
+    SelectedCollection(CollectionId),
     Unselected(),
 }
 
+// Given to a collection when it's loaded or made and never reused while the process runs, so a
+// selection follows its collection through a rename and can't land on another after a drop
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CollectionId(u64);
+
+// What to do with a collection file that can't be read when the database is opened
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum RecoveryPolicy {
+    // refuse to open the database at all
+    #[default]
+    Fail,
+    // move the file into corrupt/ and open without that collection
+    Quarantine,
+    // open without that collection and refuse every write so nothing gets overwritten
+    ReadOnly,
+}
+
+impl std::str::FromStr for RecoveryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(RecoveryPolicy::Fail),
+            "quarantine" => Ok(RecoveryPolicy::Quarantine),
+            "read-only" | "readonly" => Ok(RecoveryPolicy::ReadOnly),
+            _ => Err(format!("unknown recovery policy {}, expected fail, quarantine or read-only", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    pub recovery: RecoveryPolicy,
+    // needed for an encrypted database and refused for one that isn't
+    pub key: Option<KeySource>,
+}
+
 #[derive(Debug)]
 pub enum Response {
     Message(String),
@@ -34,170 +86,700 @@ pub struct Database {
     path: String,
     wal_manager: WALManager, 
     auth_manager: AuthManager, 
-    collections: Vec<Collection>,
-    state: DatabaseState,
+    collections: HashMap<CollectionId, Collection>,
+    // what commands, grants and the files go by
+    names: BTreeMap<String, CollectionId>,
+    next_id: u64,
+    manifest: Manifest,
+    read_only: bool,
     current_session: Option<Session>,
+    audit_log: AuditLog,
+    #[serde(skip)]
+    cipher: Option<Cipher>,
 }
 
 impl Database {
-    pub fn new(path: String) -> Database {
-        match Database::load_data(path.clone()) {
-            Ok(database) => database,
-            Err(_) => {
-                fs::create_dir_all(&path).unwrap();
-                Database { 
-                    path: path.clone(),
-                    wal_manager: WALManager::new(path.clone()),
-                    auth_manager: AuthManager::new(path.as_str()).unwrap(),
-                    collections : Vec::new(),
-                    state: DatabaseState::Unselected(),
-                    current_session: None,
-                }
+    // Opens the database in that directory. Anything that goes wrong loading it is returned rather
+    // than replaced, and a directory that was never set up with init is refused
+    pub fn open(path: String, options: OpenOptions) -> Result<Database, DatabaseError> {
+        if fs::metadata(format!("{}/users.log", path)).is_err() {
+            return Err(DatabaseError::Other(format!("{} is not a database yet, run init to create one", path)))
+        }
+        Database::load_data(path, options)
+    }
 
-            }
+    // Creates a database with its first admin. A directory that already holds a database is only
+    // taken over if nobody in it is an admin yet, which is how databases from before roles get one
+    pub fn init(path: String, username: &String, password: &String) -> Result<Database, DatabaseError> {
+        fs::create_dir_all(&path)?;
+        if fs::metadata(format!("{}/users.log", path)).is_err() {
+            AuthManager::new(&path, None)?;
+        }
+
+        let mut database = Database::load_data(path, OpenOptions::default())?;
+        if database.auth_manager.users().iter().any(|user| user.permissions == Permissions::Admin()) {
+            return Err(DatabaseError::Other(format!("{} already has an admin", database.path)))
         }
+        database.auth_manager.new_user(&database.path, username, password, Permissions::Admin())?;
+        Ok(database)
     }
 
     pub fn login(&mut self, username: String, password: String) -> Result<(), DatabaseError> {
-        let session = self.auth_manager.login(username, password)?;
-        self.current_session = Some(session);
-        self.write_and_clear_wal_log()?;
-        self.state = DatabaseState::Unselected();
+        self.current_session = Some(self.authenticate(username, password)?);
         Ok(())
     }
 
-    pub fn new_user(&mut self, username: &String, password: &String, permissions: Permissions) -> Result<(), DatabaseError> {
-        self.auth_manager.new_user(&self.path, username, password, permissions)?;
+    // Checks a user's credentials and hands back a session of their own, for front ends that
+    // serve more than one user at a time
+    pub fn authenticate(&mut self, username: String, password: String) -> Result<Session, DatabaseError> {
+        let mut attempt = self.begin_login(username, password);
+        attempt.check();
+        self.finish_login(attempt)
+    }
+
+    // The same in two halves, for front ends sharing the database between threads. Whatever's
+    // between them checks the password with LoginAttempt::check and doesn't need the database
+    pub fn begin_login(&self, username: String, password: String) -> LoginAttempt {
+        self.auth_manager.begin_login(username, password)
+    }
+
+    pub fn finish_login(&mut self, attempt: LoginAttempt) -> Result<Session, DatabaseError> {
+        let username = attempt.username().to_string();
+        let result = self.auth_manager.finish_login(&self.path, attempt);
+        self.audit(AuditEntry::new(&username, None, "LOGIN", None, result.as_ref().err()));
+        result
+    }
+
+    // Hands out a bearer token that stands in for the session until the process exits
+    pub fn create_session_token(&mut self, session: &Session) -> Result<String, DatabaseError> {
+        self.auth_manager.create_session_token(session)
+    }
+
+    pub fn authenticate_token(&self, token: &str) -> Result<Session, DatabaseError> {
+        self.auth_manager.session_from_token(token)
+    }
+
+    pub fn revoke_session_token(&mut self, token: &str) -> bool {
+        self.auth_manager.revoke_session_token(token)
+    }
+
+    // The user management commands, admins only
+    pub fn create_user(&mut self, session: &Session, username: String, password: String, permissions: Permissions) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        self.auth_manager.new_user(&self.path, &username, &password, permissions)?;
+        Ok(Response::Message(format!("{} created", username)))
+    }
+
+    pub fn drop_user(&mut self, session: &Session, username: String) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        self.auth_manager.drop_user(&self.path, &username)?;
+        Ok(Response::Message(format!("{} dropped", username)))
+    }
+
+    pub fn alter_password(&mut self, session: &Session, username: String, password: String) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        self.auth_manager.set_password(&self.path, &username, &password)?;
+        Ok(Response::Message(format!("Password for {} changed", username)))
+    }
+
+    pub fn alter_role(&mut self, session: &Session, username: String, permissions: Permissions) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        let message = format!("{} is now {}", username, permissions);
+        self.auth_manager.set_role(&self.path, &username, permissions)?;
+        Ok(Response::Message(message))
+    }
+
+    // [{"username": ..., "role": ..., "failed_attempts": ..., "locked_until": ...}], locked_until
+    // being unix time or null
+    pub fn list_users(&self, session: &Session) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        let users = self.auth_manager.users().into_iter()
+            .map(|user| serde_json::json!({
+                "username": user.username,
+                "role": user.permissions.to_string(),
+                "failed_attempts": user.failed_attempts,
+                "locked_until": (user.locked_until > 0).then_some(user.locked_until),
+            }))
+            .collect();
+        Ok(Response::Value(Value::Array(users)))
+    }
+
+    pub fn unlock_user(&mut self, session: &Session, username: String) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        self.auth_manager.unlock(&self.path, &username)?;
+        Ok(Response::Message(format!("{} unlocked", username)))
+    }
+
+    // Anyone can make tokens for themselves, only an admin can make them for someone else
+    pub fn create_token(&mut self, session: &Session, owner: Option<String>, lifetime: Option<u64>, read_only: bool) -> Result<Response, DatabaseError> {
+        let owner = owner.unwrap_or(session.user.clone());
+        self.manage_tokens(session, &owner)?;
+        let (token, stored) = self.auth_manager.create_api_token(&self.path, &owner, lifetime.unwrap_or(TOKEN_LIFETIME), read_only)?;
+        let mut response = Database::token_json(&stored);
+        // the only time it's ever shown
+        response["token"] = Value::String(token);
+        Ok(Response::Value(response))
+    }
+
+    // Admins see everyone's tokens, everyone else only sees their own
+    pub fn list_tokens(&self, session: &Session) -> Result<Response, DatabaseError> {
+        let admin = self.auth_manager.access(session, None) >= Some(Access::Admin);
+        let tokens = self.auth_manager.api_tokens().into_iter()
+            .filter(|token| admin || token.owner == session.user)
+            .map(Database::token_json)
+            .collect();
+        Ok(Response::Value(Value::Array(tokens)))
+    }
+
+    pub fn revoke_token(&mut self, session: &Session, id: String) -> Result<Response, DatabaseError> {
+        let owner = self.auth_manager.api_tokens().into_iter()
+            .find(|token| token.id == id)
+            .map(|token| token.owner.clone())
+            .ok_or(DatabaseError::ValueNotFound(format!("token {}", id)))?;
+        self.manage_tokens(session, &owner)?;
+        self.auth_manager.revoke_api_token(&self.path, &id)?;
+        Ok(Response::Message(format!("Token {} revoked", id)))
+    }
+
+    // A read-only token can't be used to make or revoke tokens, or it could make itself a writable one
+    fn manage_tokens(&self, session: &Session, owner: &str) -> Result<(), DatabaseError> {
+        if session.read_only {
+            return Err(DatabaseError::PermissionDenied(format!("{} is logged in with a read-only token", session.user)))
+        }
+        if owner != session.user {
+            self.authorize(session, None, Access::Admin)?;
+        }
         Ok(())
     }
 
-    pub fn insert(&mut self, key : String, value: Value) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string())) 
+    fn token_json(token: &ApiToken) -> Value {
+        serde_json::json!({
+            "id": token.id,
+            "owner": token.owner,
+            "created": token.created,
+            "expires": (token.expires != u64::MAX).then_some(token.expires),
+            "scope": if token.read_only { "read" } else { "write" },
+        })
+    }
+
+    pub fn insert(&mut self, session: &mut Session, key: Key, value: Value) -> Result<Response, DatabaseError> {
+        let (collection, key) = self.target(session, &key)?;
+        self.authorize(session, Some(collection), Access::Write)?;
+        self.put(session, collection, key, value)?;
+        Ok(Response::Value(Value::Null))
+    }
+
+    // Works out the new document from what this session sees and logs it as a plain INSERT, so
+    // replaying the WAL doesn't have to run the update again. Returns the updated document
+    pub fn update(&mut self, session: &mut Session, key: Key, update: Update) -> Result<Response, DatabaseError> {
+        let (collection, key) = self.target(session, &key)?;
+        self.authorize(session, Some(collection), Access::Write)?;
+        let current = self.read_value(session, collection, &key).ok_or(DatabaseError::ValueNotFound(key.clone()))?;
+        let value = update.apply(current)?;
+        self.put(session, collection, key, value.clone())?;
+        Ok(Response::Value(value))
+    }
+
+    // Checks the value against the schema and logs it, or holds it back in the open transaction
+    fn put(&mut self, session: &mut Session, collection: CollectionId, key: String, value: Value) -> Result<(), DatabaseError> {
+        if let Some(schema) = self.collections[&collection].schema() {
+            schema.validate(&value)?;
+        }
+        let entry = WALEntry::new(self.collections[&collection].name.clone(),"INSERT".to_string(), key.clone(), Some(value.clone()));
+        if let Some(transaction) = session.transaction.as_mut() {
+            transaction.push(entry);
+            return Ok(())
         }
+        self.wal_manager.append(&entry)?;
+        self.collection_mut(collection).insert(key, value);
+        Ok(())
+    }
 
-        if &self.current_session.as_ref().unwrap().permissions == &Permissions::Guest() {
-            return Err(DatabaseError::PermissionDenied("Guest permissions cannot write data".to_string()))
+    pub fn get(&self, session: &Session, key: Key) -> Result<Response, DatabaseError> {
+        let (collection, key) = self.target(session, &key)?;
+        self.authorize(session, Some(collection), Access::Read)?;
+        match self.read_value(session, collection, &key) {
+            Some(value) => Ok(Response::Value(value)),
+            None => Err(DatabaseError::ValueNotFound(key))
         }
+    }
 
-        match self.state {
-            DatabaseState::Unselected() => Err(DatabaseError::CollectionError("Select a collection".to_string())),
-            DatabaseState::SelectedCollection(collection) => {
-                let entry = WALEntry::new(self.collections[collection].name.clone(),"INSERT".to_string(), key.clone(), Some(value.clone()));
-                entry.log(format!("{}/wal.log", self.path).as_str());
-                self.collections[collection].insert(key.clone(), value);
-                Ok(Response::Value(Value::Null))
-            },
+    pub fn delete(&mut self, session: &mut Session, key: Key) -> Result<Response, DatabaseError> {
+        let (collection, key) = self.target(session, &key)?;
+        self.authorize(session, Some(collection), Access::Write)?;
+        let entry = WALEntry::new(self.collections[&collection].name.clone(),"DELETE".to_string(), key.clone(), None);
+        let current = self.read_value(session, collection, &key);
+        if let Some(transaction) = session.transaction.as_mut() {
+            let value = current.ok_or(DatabaseError::ValueNotFound(key))?;
+            transaction.push(entry);
+            return Ok(Response::Value(value))
+        }
+        self.wal_manager.append(&entry)?;
+        match self.collection_mut(collection).delete(key.clone()) {
+            Some(value) => Ok(Response::Value(value)),
+            None => Err(DatabaseError::ValueNotFound(key))
         }
     }
 
-    pub fn get(&self, key : String) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string()))
+    // The value as this session sees it, including anything written in its open transaction
+    fn read_value(&self, session: &Session, collection: CollectionId, key: &String) -> Option<Value> {
+        let pending = session.transaction.as_ref()
+            .and_then(|transaction| transaction.iter().rev()
+                .find(|entry| entry.collection == self.collections[&collection].name && &entry.key == key));
+
+        match pending {
+            Some(entry) => entry.value.as_ref().and_then(|value| serde_json::from_str(value).ok()),
+            None => self.collections[&collection].get(key.clone()),
         }
+    }
 
-        match self.state {
-            DatabaseState::Unselected() => Err(DatabaseError::CollectionError("Select a collection".to_string())),
-            DatabaseState::SelectedCollection(collection) => {
-                match self.collections[collection].get(key.clone()) {
-                    Some(value) => Ok(Response::Value(value)),
-                    None => Err(DatabaseError::ValueNotFound(key))
+    // The collection a key command works on and the key within it. A bare key is read as
+    // collection.key when the part before the first dot names a collection, a quoted one is always
+    // in the selected collection
+    fn target(&self, session: &Session, key: &Key) -> Result<(CollectionId, String), DatabaseError> {
+        match key {
+            Key::In(name, key) => {
+                let collection = self.find_collection_by_name(name).ok_or(DatabaseError::CollectionNotFound(name.clone()))?;
+                Ok((collection, key.clone()))
+            }
+            Key::Bare(key) => {
+                if let Some((name, rest)) = key.split_once('.') && let Some(collection) = self.find_collection_by_name(name) {
+                    return Ok((collection, rest.to_string()))
                 }
-            },
+                Ok((self.selected(session)?, key.clone()))
+            }
+            Key::Quoted(key) => Ok((self.selected(session)?, key.clone())),
         }
     }
 
-    pub fn delete(&mut self, key: String) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string())) 
+    pub fn begin(&mut self, session: &mut Session) -> Result<Response, DatabaseError> {
+        if session.transaction.is_some() {
+            return Err(DatabaseError::Other("Transaction already in progress".to_string()))
         }
-        if &self.current_session.as_ref().unwrap().permissions == &Permissions::Guest() {
-            return Err(DatabaseError::PermissionDenied("Guest permissions cannot write data".to_string()))
+        session.transaction = Some(Vec::new());
+        Ok(Response::Message("Transaction started".to_string()))
+    }
+
+    // Logs the whole transaction as one batch before applying any of it
+    pub fn commit(&mut self, session: &mut Session) -> Result<Response, DatabaseError> {
+        let transaction = session.transaction.take()
+            .ok_or(DatabaseError::Other("No transaction in progress".to_string()))?;
+        // another session may have dropped or renamed a collection in the meantime
+        if let Some(entry) = transaction.iter().find(|entry| self.find_collection_by_name(&entry.collection).is_none()) {
+            return Err(DatabaseError::CollectionNotFound(entry.collection.clone()))
         }
-        match self.state {
-            DatabaseState::Unselected() => Err(DatabaseError::CollectionError("Select a collection".to_string())),
-            DatabaseState::SelectedCollection(collection) => {
-                let entry = WALEntry::new(self.collections[collection].name.clone(),"DELETE".to_string(), key.clone(), None); 
-                entry.log(format!("{}/wal.log", self.path).as_str());
-                match self.collections[collection].delete(key.clone()) {
-                    Some(value) => Ok(Response::Value(value)),
-                    None => Err(DatabaseError::ValueNotFound(key))
-                }
-            },
+        // or taken away the user's write access to one of them
+        let staged: HashSet<CollectionId> = transaction.iter().filter_map(|entry| self.find_collection_by_name(&entry.collection)).collect();
+        for collection in staged {
+            self.authorize(session, Some(collection), Access::Write)?;
+        }
+        // or set a schema the staged writes don't fit
+        for entry in transaction.iter().filter(|entry| entry.operation == "INSERT") {
+            let schema = self.find_collection_by_name(&entry.collection).and_then(|id| self.collections[&id].schema());
+            if let (Some(schema), Some(value)) = (schema, &entry.value) {
+                schema.validate(&serde_json::from_str(value)?).map_err(|e| match e {
+                    DatabaseError::ValidationError { path, reason } => DatabaseError::ValidationError { path: format!("{} at {}", entry.key, path), reason },
+                    e => e,
+                })?;
+            }
+        }
+
+        if !transaction.is_empty() {
+            self.wal_manager.append_batch(&transaction)?;
         }
+        let count = transaction.len();
+        for entry in transaction {
+            self.apply_entry(entry)?;
+        }
+        Ok(Response::Message(format!("Committed {} operations", count)))
     }
 
-    pub fn select(&mut self, collection: String) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string())) 
+    pub fn rollback(&mut self, session: &mut Session) -> Result<Response, DatabaseError> {
+        match session.transaction.take() {
+            Some(transaction) => Ok(Response::Message(format!("Rolled back {} operations", transaction.len()))),
+            None => Err(DatabaseError::Other("No transaction in progress".to_string())),
         }
+    }
+
+    pub fn select(&mut self, session: &mut Session, collection: String) -> Result<Response, DatabaseError> {
         match self.find_collection_by_name(&collection) {
-            Some(index) => {
-                self.state = DatabaseState::SelectedCollection(index);
+            Some(id) => {
+                self.authorize(session, Some(id), Access::Read)?;
+                session.state = DatabaseState::SelectedCollection(id);
                 Ok(Response::Message(format!("{} selected", collection)))
             },
             None => Err(DatabaseError::CollectionNotFound(collection))
         }
     }
 
-    pub fn new_collection(&mut self, name: &String) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string())) 
-        }
-        if &self.current_session.as_ref().unwrap().permissions == &Permissions::Guest() {
-            return Err(DatabaseError::PermissionDenied("Guest permissions cannot write data".to_string()))
-        }
+    pub fn new_collection(&mut self, session: &mut Session, name: &String) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Write)?;
+        self.check_name(name)?;
         let collection = Collection::new(name.clone());
-        self.collections.push(collection);
-        fs::File::create(format!("{}/{}.db", self.path, name))?;
+        self.write_snapshot(&collection, self.manifest.generation)?;
+        self.add_collection(collection);
         Ok(Response::Message(format!("{} created", name)))
     }
 
-    pub fn which(&self, key: String) -> Result<Response, DatabaseError> {
-        if self.current_session.is_none() {
-            return Err(DatabaseError::UserError("Login to access the database".to_string())) 
+    fn add_collection(&mut self, collection: Collection) -> CollectionId {
+        let id = CollectionId(self.next_id);
+        self.next_id += 1;
+        self.names.insert(collection.name.clone(), id);
+        self.collections.insert(id, collection);
+        id
+    }
+
+    fn remove_collection(&mut self, id: CollectionId) {
+        if let Some(collection) = self.collections.remove(&id) {
+            self.names.remove(&collection.name);
+        }
+    }
+
+    // Only for collections already looked up through names, target or selected
+    fn collection_mut(&mut self, id: CollectionId) -> &mut Collection {
+        self.collections.get_mut(&id).expect("collection ids are looked up before use")
+    }
+
+    // The name ends up as a file name in the data directory, so only letters, digits, _ and - are
+    // allowed and it can't be taken already
+    fn check_name(&self, name: &str) -> Result<(), DatabaseError> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(DatabaseError::CollectionError(format!(
+                "{:?} isn't a valid collection name, use up to {} letters, digits, _ and -", name, MAX_NAME_LEN)))
+        }
+        if self.find_collection_by_name(name).is_some() {
+            return Err(DatabaseError::CollectionError(format!("{} already exists", name)))
+        }
+        Ok(())
+    }
+
+    // The collections the session can read with how many keys each holds and the size of its file
+    pub fn list_collections(&self, session: &Session) -> Result<Response, DatabaseError> {
+        let listed: Vec<Value> = self.names.iter()
+            .filter(|(name, _)| self.auth_manager.access(session, Some(name)) >= Some(Access::Read))
+            .map(|(_, id)| &self.collections[id])
+            .map(|collection| {
+            let bytes = fs::metadata(format!("{}/{}.db", self.path, collection.name)).map(|file| file.len()).unwrap_or(0);
+            serde_json::json!({ "name": collection.name, "keys": collection.len(), "bytes": bytes })
+        }).collect();
+        Ok(Response::Value(Value::Array(listed)))
+    }
+
+    // Dropping and renaming are logged and then checkpointed straight away, which writes the
+    // collections that are left, empties the WAL of everything that named the old collection and
+    // removes its file. Sessions that had a dropped collection selected are told so the next time
+    // they use it
+    pub fn drop_collection(&mut self, session: &mut Session, name: String) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&name).ok_or(DatabaseError::CollectionNotFound(name.clone()))?;
+        self.authorize(session, Some(id), Access::Admin)?;
+        Database::outside_transaction(session)?;
+
+        let entry = WALEntry::new(name.clone(), "DROP COLLECTION".to_string(), String::new(), None);
+        self.wal_manager.append(&entry)?;
+        self.apply_entry(entry)?;
+        self.auth_manager.move_grants(&self.path, &name, None)?;
+        self.checkpoint()?;
+        Ok(Response::Message(format!("{} dropped", name)))
+    }
+
+    pub fn rename_collection(&mut self, session: &mut Session, name: String, to: String) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&name).ok_or(DatabaseError::CollectionNotFound(name.clone()))?;
+        self.authorize(session, Some(id), Access::Admin)?;
+        Database::outside_transaction(session)?;
+        self.check_name(&to)?;
+
+        let entry = WALEntry::new(name.clone(), "RENAME COLLECTION".to_string(), to.clone(), None);
+        self.wal_manager.append(&entry)?;
+        self.apply_entry(entry)?;
+        self.auth_manager.move_grants(&self.path, &name, Some(&to))?;
+        self.checkpoint()?;
+        Ok(Response::Message(format!("{} renamed to {}", name, to)))
+    }
+
+    // Writes held back in a transaction name their collection, which has to stay put until they commit
+    fn outside_transaction(session: &Session) -> Result<(), DatabaseError> {
+        match session.transaction {
+            Some(_) => Err(DatabaseError::Other("Commit or roll back the transaction first".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn create_index(&mut self, session: &mut Session, collection: String, path: String) -> Result<Response, DatabaseError> {
+        self.change_index(session, "CREATE INDEX", collection, path)
+    }
+
+    pub fn drop_index(&mut self, session: &mut Session, collection: String, path: String) -> Result<Response, DatabaseError> {
+        self.change_index(session, "DROP INDEX", collection, path)
+    }
+
+    fn change_index(&mut self, session: &mut Session, operation: &str, collection: String, path: String) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&collection).ok_or(DatabaseError::CollectionNotFound(collection.clone()))?;
+        self.authorize(session, Some(id), Access::Write)?;
+        let exists = self.collections[&id].index_paths().contains(&path);
+        match (operation, exists) {
+            ("CREATE INDEX", true) => return Err(DatabaseError::CollectionError(format!("{} is already indexed on {}", collection, path))),
+            ("DROP INDEX", false) => return Err(DatabaseError::CollectionError(format!("{} has no index on {}", collection, path))),
+            _ => (),
+        }
+
+        let entry = WALEntry::new(collection.clone(), operation.to_string(), path.clone(), None);
+        self.wal_manager.append(&entry)?;
+        self.apply_entry(entry)?;
+        match operation {
+            "CREATE INDEX" => Ok(Response::Message(format!("Index on {} created", path))),
+            _ => Ok(Response::Message(format!("Index on {} dropped", path))),
+        }
+    }
+
+    // Sets, takes off or shows the collection's schema. A schema is only set once every document
+    // already in the collection fits it
+    pub fn schema(&mut self, session: &mut Session, collection: String, schema: Option<Value>) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&collection).ok_or(DatabaseError::CollectionNotFound(collection.clone()))?;
+        let Some(schema) = schema else {
+            self.authorize(session, Some(id), Access::Read)?;
+            return Ok(Response::Value(self.collections[&id].schema().map(|schema| schema.source().clone()).unwrap_or(Value::Null)))
+        };
+        self.authorize(session, Some(id), Access::Admin)?;
+
+        let value = match schema {
+            Value::Null => None,
+            schema => {
+                let parsed = Schema::parse(schema.clone())?;
+                for (key, document) in self.collections[&id].documents() {
+                    parsed.validate(&document).map_err(|e| match e {
+                        DatabaseError::ValidationError { path, reason } => DatabaseError::ValidationError { path: format!("{} at {}", key, path), reason },
+                        e => e,
+                    })?;
+                }
+                Some(schema)
+            }
+        };
+        let removed = value.is_none();
+        let entry = WALEntry::new(collection.clone(), "SCHEMA".to_string(), String::new(), value);
+        self.wal_manager.append(&entry)?;
+        self.apply_entry(entry)?;
+        match removed {
+            true => Ok(Response::Message(format!("Schema on {} removed", collection))),
+            false => Ok(Response::Message(format!("Schema on {} set", collection))),
+        }
+    }
+
+    // Runs the query against the collection it names or else the selected one, as an array of
+    // {"key": ..., "value": ...}
+    pub fn find(&self, session: &Session, query: Query) -> Result<Response, DatabaseError> {
+        let id = match &query.collection {
+            Some(name) => self.find_collection_by_name(name).ok_or(DatabaseError::CollectionNotFound(name.clone()))?,
+            None => self.selected(session)?,
+        };
+        self.authorize(session, Some(id), Access::Read)?;
+        Ok(Response::Value(query.run(&self.collections[&id])))
+    }
+
+    // Keys starting with the prefix in order, a page at a time as {"items": [...], "cursor": ...}.
+    // The cursor is null on the last page, otherwise it goes back in as after for the next one.
+    // The prefix can name the collection like a key does
+    pub fn scan(&self, session: &Session, prefix: Key, limit: Option<usize>, after: Option<String>) -> Result<Response, DatabaseError> {
+        let (id, prefix) = self.target(session, &prefix)?;
+        self.authorize(session, Some(id), Access::Read)?;
+        let collection = &self.collections[&id];
+        let from = match &after {
+            Some(after) if after >= &prefix => Bound::Excluded(after.as_str()),
+            _ => Bound::Included(prefix.as_str()),
+        };
+        let entries = collection.range(from, Bound::Unbounded).take_while(|(key, _)| key.starts_with(&prefix));
+        Ok(Response::Value(Database::page(entries, limit)))
+    }
+
+    // Keys from from up to but not including to, paged like scan. Both ends have to be in the
+    // same collection
+    pub fn range(&self, session: &Session, from: Key, to: Key, limit: Option<usize>, after: Option<String>) -> Result<Response, DatabaseError> {
+        let ((id, from), (other, to)) = (self.target(session, &from)?, self.target(session, &to)?);
+        if id != other {
+            return Err(DatabaseError::CollectionError("RANGE has to start and end in the same collection".to_string()))
+        }
+        self.authorize(session, Some(id), Access::Read)?;
+        let collection = &self.collections[&id];
+        let from = match &after {
+            Some(after) if after >= &from => Bound::Excluded(after.as_str()),
+            _ => Bound::Included(from.as_str()),
+        };
+        let entries = collection.range(from, Bound::Excluded(to.as_str()));
+        Ok(Response::Value(Database::page(entries, limit)))
+    }
+
+    fn page<'a>(entries: impl Iterator<Item = (&'a String, &'a Value)>, limit: Option<usize>) -> Value {
+        let mut entries = entries.peekable();
+        let items: Vec<Value> = entries.by_ref()
+            .take(limit.unwrap_or(PAGE_SIZE))
+            .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+            .collect();
+        let cursor = match entries.peek() {
+            Some(_) => items.last().map(|item| item["key"].clone()).unwrap_or(Value::Null),
+            None => Value::Null,
+        };
+        serde_json::json!({ "items": items, "cursor": cursor })
+    }
+
+    // Every permission check goes through here. Without a collection it's about the database as a
+    // whole, which only the session's role decides
+    fn authorize(&self, session: &Session, collection: Option<CollectionId>, access: Access) -> Result<(), DatabaseError> {
+        if self.read_only && access > Access::Read {
+            return Err(DatabaseError::PermissionDenied("Database was opened read-only".to_string()))
+        }
+        let collection = collection.map(|id| self.collections[&id].name.as_str());
+        self.auth_manager.authorize(session, collection, access)
+    }
+
+    // Changing grants on a collection takes admin access to it
+    pub fn grant(&mut self, session: &mut Session, access: Access, collection: String, grantee: Grantee) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&collection).ok_or(DatabaseError::CollectionNotFound(collection.clone()))?;
+        self.authorize(session, Some(id), Access::Admin)?;
+        let message = format!("Granted {} on {} to {}", access, collection, grantee);
+        self.auth_manager.grant(&self.path, grantee, collection, access)?;
+        Ok(Response::Message(message))
+    }
+
+    pub fn revoke(&mut self, session: &mut Session, access: Access, collection: String, grantee: Grantee) -> Result<Response, DatabaseError> {
+        let id = self.find_collection_by_name(&collection).ok_or(DatabaseError::CollectionNotFound(collection.clone()))?;
+        self.authorize(session, Some(id), Access::Admin)?;
+        self.auth_manager.revoke(&self.path, &grantee, &collection, access)?;
+        Ok(Response::Message(format!("Revoked {} on {} from {}", access, collection, grantee)))
+    }
+
+    // The session's selected collection, as long as nobody has dropped it since
+    fn selected(&self, session: &Session) -> Result<CollectionId, DatabaseError> {
+        match session.state {
+            DatabaseState::SelectedCollection(id) if self.collections.contains_key(&id) => Ok(id),
+            DatabaseState::SelectedCollection(_) => Err(DatabaseError::CollectionError("The selected collection was dropped, select another".to_string())),
+            DatabaseState::Unselected() => Err(DatabaseError::CollectionError("Select a collection".to_string())),
         }
+    }
 
-        if key == "collection".to_string() {
-            match self.state {
-                DatabaseState::SelectedCollection(index) => return Ok(Response::Message(format!("{} selected", self.collections.get(index).unwrap().name))),
-                DatabaseState::Unselected() => return Ok(Response::Message("No collection selected".to_string()))
+    pub fn which(&self, session: &Session, key: String) -> Result<Response, DatabaseError> {
+        if key == "collection" {
+            match self.selected(session) {
+                Ok(id) => return Ok(Response::Message(format!("{} selected", self.collections[&id].name))),
+                Err(_) => return Ok(Response::Message("No collection selected".to_string()))
             }
         };
-        if key == "path".to_string() {
+        if key == "path" {
             return Ok(Response::Message(self.path.clone()))
         };
-        if key == "user".to_string() {
-            return Ok(Response::Message(self.current_session.as_ref().unwrap().user.clone()))
+        if key == "user" {
+            return Ok(Response::Message(session.user.clone()))
         }
         Err(DatabaseError::ValueNotFound(format!("{} invalid", key)))
     }
 
-    pub fn find_collection_by_name(&self, name: &String) -> Option<usize> {
-        Some(self.collections.iter().position(|c| &c.name == name)?)
+    // A collection to work with directly by name, as the logged in user but without touching
+    // which collection they have selected
+    pub fn collection(&mut self, name: &str) -> Result<CollectionHandle<'_>, DatabaseError> {
+        if self.current_session.is_none() {
+            return Err(DatabaseError::UserError("Login to access the database".to_string()))
+        }
+        match self.find_collection_by_name(name) {
+            Some(id) => Ok(CollectionHandle { database: self, id }),
+            None => Err(DatabaseError::CollectionNotFound(name.to_string())),
+        }
+    }
+
+    pub fn find_collection_by_name(&self, name: &str) -> Option<CollectionId> {
+        self.names.get(name).copied()
     }
 
     pub fn save_data(&mut self) -> Result<(), DatabaseError> {
-        if &self.current_session.as_ref().unwrap().permissions == &Permissions::Guest() {
-            return Err(DatabaseError::PermissionDenied("Guest permissions cannot write data".to_string()))
+        let session = self.current_session.as_ref()
+            .ok_or(DatabaseError::UserError("Login to access the database".to_string()))?;
+        self.authorize(session, None, Access::Write)?;
+        self.checkpoint()
+    }
+
+    // Whether the WAL holds writes the snapshots don't have yet. Front ends check it before
+    // checkpointing, so a session that only read doesn't have to be allowed to write
+    pub fn has_unsaved_changes(&self) -> bool {
+        !self.read_only && self.wal_manager.last_lsn() > self.manifest.wal_lsn
+    }
+
+    // How big the WAL has grown since the last checkpoint, in bytes
+    pub fn wal_size(&self) -> Result<u64, DatabaseError> {
+        self.wal_manager.size()
+    }
+
+    // Writes every collection to disk and empties the WAL
+    pub fn checkpoint(&mut self) -> Result<(), DatabaseError> {
+        if self.read_only {
+            return Err(DatabaseError::PermissionDenied("Database was opened read-only".to_string()))
         }
 
-        self.write_and_clear_wal_log()?;
         fs::create_dir_all(self.path.clone())?;
-        for collection in &self.collections {
-            let encoded : Vec<u8> = bincode::serialize(&collection)?;
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .open(format!("{}/{}.db", &self.path, &collection.name))?;
+        let generation = self.manifest.generation + 1;
+        for collection in self.collections.values() {
+            self.write_snapshot(collection, generation)?;
+        }
+
+        // only once the manifest points at the new generation is the WAL safe to throw away
+        self.manifest = Manifest { generation, wal_lsn: self.wal_manager.last_lsn() };
+        self.manifest.save(&self.path)?;
+        self.wal_manager.clear()?;
 
-            file.write_all(&encoded)?;
+        // files left behind by collections that were dropped or renamed since the last checkpoint
+        for entry in fs::read_dir(&self.path)? {
+            let file = entry?.path();
+            let Some(stem) = file.file_stem().and_then(|stem| stem.to_str()) else { continue };
+            if file.is_file() && file.extension() == Some("db".as_ref()) && self.find_collection_by_name(stem).is_none() {
+                fs::remove_file(&file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_snapshot(&self, collection: &Collection, generation: u64) -> Result<(), DatabaseError> {
+        let encoded = self.snapshot(collection, generation, self.cipher.as_ref())?;
+        storage::write_atomic(format!("{}/{}.db", &self.path, &collection.name), &encoded)
+    }
+
+    fn snapshot(&self, collection: &Collection, generation: u64, cipher: Option<&Cipher>) -> Result<Vec<u8>, DatabaseError> {
+        let header = SnapshotHeader { generation, wal_lsn: self.wal_manager.last_lsn() };
+        crypto::seal_file(cipher, storage::encode_snapshot(header, collection)?)
+    }
+
+    // Re-encrypts the collections, users and audit log under a new key, or decrypts them for good with None.
+    // It checkpoints first so nothing is left in the WAL under the old key. Whoever holds the
+    // current key can do this, the same as whoever can read the files
+    pub fn rekey(&mut self, key: Option<&KeySource>) -> Result<(), DatabaseError> {
+        self.checkpoint()?;
+        let (cipher, header) = crypto::create(key)?.unzip();
+
+        for collection in self.collections.values() {
+            let encoded = self.snapshot(collection, self.manifest.generation, cipher.as_ref())?;
+            storage::write_atomic(format!("{}/{}.db.rekey", &self.path, &collection.name), &encoded)?;
         }
+        self.auth_manager.save_as(&format!("{}/users.log.rekey", &self.path), cipher.as_ref())?;
+        self.audit_log.rekey(cipher.as_ref())?;
 
+        // from here on the files are read with the new key
+        crypto::save_header(&self.path, header.as_deref())?;
+        crypto::finish_rekey(&self.path, cipher.as_ref())?;
+        self.auth_manager.set_cipher(cipher.clone());
+        self.wal_manager.set_cipher(cipher.clone());
+        self.audit_log.set_cipher(cipher.clone());
+        self.cipher = cipher;
         Ok(())
     }
 
-    pub fn load_data(path : String) -> Result<Self, DatabaseError> {
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn load_data(path : String, options: OpenOptions) -> Result<Self, DatabaseError> {
+        let cipher = crypto::load(&path, options.key.as_ref())?;
+        crypto::finish_rekey(&path, cipher.as_ref())?;
+        let manifest = Manifest::load(&path)?;
         let mut collections : Vec<Collection> = Vec::new();
+        let mut snapshot_lsns = HashMap::new();
+        let mut read_only = false;
+        // collections whose files were skipped, their logged writes mustn't bring them back
+        let mut unreadable = HashSet::new();
 
         for entry in fs::read_dir(&path)? {
             let path = entry?.path();
@@ -208,71 +790,984 @@ impl Database {
 
                 let mut contents = Vec::new();
                 file.read_to_end(&mut contents)?;
-                let collection : Collection = match bincode::deserialize(&contents) {
-                    Ok(collection) => collection,
-                    Err(e) => {
-                        println!("{}", e);
-                        Collection::new(path.file_stem().unwrap().to_str().unwrap().to_string())
+                std::mem::drop(file);
+
+                // NEW used to leave an empty file behind until the first save
+                if contents.is_empty() {
+                    collections.push(Collection::new(path.file_stem().unwrap().to_str().unwrap().to_string()));
+                    continue
+                }
+
+                let file = path.to_str().unwrap();
+                let decoded = crypto::open_file(cipher.as_ref(), file, contents)
+                    .and_then(|contents| storage::decode_snapshot(file, &contents));
+                let collection : Collection = match decoded {
+                    // a dropped or renamed collection whose file a checkpoint didn't get to remove
+                    Ok((header, _)) if header.generation < manifest.generation => continue,
+                    Ok((header, collection)) => {
+                        snapshot_lsns.insert(collection.name.clone(), header.wal_lsn);
+                        collection
+                    }
+                    Err(e) => match options.recovery {
+                        RecoveryPolicy::Fail => return Err(e),
+                        RecoveryPolicy::Quarantine => {
+                            unreadable.insert(path.file_stem().unwrap().to_str().unwrap().to_string());
+                            let moved_to = Database::quarantine(&path)?;
+                            eprintln!("{}, moved to {}", e, moved_to);
+                            continue
+                        }
+                        RecoveryPolicy::ReadOnly => {
+                            unreadable.insert(path.file_stem().unwrap().to_str().unwrap().to_string());
+                            eprintln!("{}, opening read-only", e);
+                            read_only = true;
+                            continue
+                        }
                     }
                 };
                 collections.push(collection);
-                std::mem::drop(file);
             }
         }
 
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .open(format!("{}/users.log", path.clone()))?;
+        let auth_manager = AuthManager::load(&path, cipher.clone())?;
 
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)?;
-        std::mem::drop(file);
-        let auth_manager: AuthManager = bincode::deserialize(&contents)?;
+        let mut wal_manager = WALManager::new(path.clone(), cipher.clone());
+        wal_manager.resume_after(manifest.wal_lsn);
 
-        let database = Database{ 
-            collections, 
+        let mut database = Database{ 
+            collections: HashMap::new(),
+            names: BTreeMap::new(),
+            next_id: 0,
             path: path.clone(), 
             auth_manager, 
-            wal_manager: WALManager::new(path), 
-            state : DatabaseState::Unselected(),
+            wal_manager,
+            manifest,
+            read_only,
             current_session: None,
+            audit_log: AuditLog::new(path.clone(), cipher.clone()),
+            cipher,
         };
+        for collection in collections {
+            if database.find_collection_by_name(&collection.name).is_some() {
+                eprintln!("{} is in more than one file, only one of them was loaded", collection.name);
+                continue
+            }
+            database.add_collection(collection);
+        }
 
-
+        database.replay_wal(&snapshot_lsns, unreadable)?;
         Ok(database)
     }
 
+    // Runs a command as the user logged in with login
     pub fn operate_db(&mut self, command: Command) -> Result<Response, DatabaseError> {
+        let mut session = self.current_session.take()
+            .ok_or(DatabaseError::UserError("Login to access the database".to_string()))?;
+        let result = self.execute(&mut session, command);
+        self.current_session = Some(session);
+        result
+    }
+
+    // Runs the command and records who ran it and whether it worked in the audit log
+    pub fn execute(&mut self, session: &mut Session, command: Command) -> Result<Response, DatabaseError> {
+        let (operation, collection, key) = command.describe();
+        // a qualified key is recorded as the collection it named and the key within it, the same
+        // as if the collection had been selected
+        let target = |key: &Key| self.target(session, key).ok().map(|(id, key)| (self.collections[&id].name.clone(), key));
+        let (collection, key) = match &command {
+            Command::INSERT(key, _) | Command::UPDATE(key, _) | Command::GET(key) | Command::DELETE(key) | Command::SCAN(key, _, _) => {
+                match target(key) {
+                    Some((collection, key)) => (Some(collection), Some(key)),
+                    None => (None, Some(key.to_string())),
+                }
+            }
+            Command::RANGE(from, to, _, _) => match (target(from), target(to)) {
+                (Some((collection, from)), Some((_, to))) => (Some(collection), Some(format!("{}..{}", from, to))),
+                _ => (None, key),
+            },
+            _ => (collection.or_else(|| self.selected(session).ok().map(|id| self.collections[&id].name.clone())), key),
+        };
+
+        let result = self.dispatch(session, command);
+        self.audit(AuditEntry::new(&session.user, collection, operation, key, result.as_ref().err()));
+        result
+    }
+
+    // A command that ran shouldn't be reported as failed just because it couldn't be audited
+    fn audit(&self, entry: AuditEntry) {
+        if let Err(e) = self.audit_log.record(&entry) {
+            eprintln!("audit log: {}", e);
+        }
+    }
+
+    pub fn audit_entries(&self, session: &Session, filter: AuditFilter) -> Result<Response, DatabaseError> {
+        self.authorize(session, None, Access::Admin)?;
+        let entries = self.audit_log.read(&filter)?;
+        Ok(Response::Value(serde_json::to_value(entries)?))
+    }
+
+    fn dispatch(&mut self, session: &mut Session, command: Command) -> Result<Response, DatabaseError> {
         match command {
-            Command::INSERT(key, value) => self.insert(key, value),
-            Command::GET(key) => self.get(key),
-            Command::DELETE(key) => self.delete(key),
-            Command::SELECT(key) => self.select(key),
-            Command::NEW(key) => self.new_collection(&key),
-            Command::WHICH(key) => self.which(key),
-            Command::ERROR() => Err(DatabaseError::Other("syntax error".to_string())),
-        }
-    }
-
-    pub fn write_and_clear_wal_log(&mut self) -> Result<(), DatabaseError> {
-        let logs = self.wal_manager.read_wal_log();
-        if logs.is_ok() {
-            for log in logs.expect("wal.log is not empty") { 
-                self.select(log.collection.clone()).unwrap();
-                let operation = log.convert_to_operation();
-                self.operate_db(operation)?;
+            Command::INSERT(key, value) => self.insert(session, key, value),
+            Command::UPDATE(key, update) => self.update(session, key, update),
+            Command::GET(key) => self.get(session, key),
+            Command::DELETE(key) => self.delete(session, key),
+            Command::SELECT(key) => self.select(session, key),
+            Command::NEW(key) => self.new_collection(session, &key),
+            Command::WHICH(key) => self.which(session, key),
+            Command::BEGIN() => self.begin(session),
+            Command::COMMIT() => self.commit(session),
+            Command::ROLLBACK() => self.rollback(session),
+            Command::CREATEINDEX(collection, path) => self.create_index(session, collection, path),
+            Command::DROPINDEX(collection, path) => self.drop_index(session, collection, path),
+            Command::FIND(query) => self.find(session, query),
+            Command::SCAN(prefix, limit, after) => self.scan(session, prefix, limit, after),
+            Command::RANGE(from, to, limit, after) => self.range(session, from, to, limit, after),
+            Command::GRANT(access, collection, grantee) => self.grant(session, access, collection, grantee),
+            Command::REVOKE(access, collection, grantee) => self.revoke(session, access, collection, grantee),
+            Command::CREATEUSER(username, password, permissions) => self.create_user(session, username, password, permissions),
+            Command::DROPUSER(username) => self.drop_user(session, username),
+            Command::ALTERPASSWORD(username, password) => self.alter_password(session, username, password),
+            Command::ALTERROLE(username, permissions) => self.alter_role(session, username, permissions),
+            Command::LISTUSERS() => self.list_users(session),
+            Command::UNLOCKUSER(username) => self.unlock_user(session, username),
+            Command::AUDIT(filter) => self.audit_entries(session, filter),
+            Command::CREATETOKEN(owner, lifetime, read_only) => self.create_token(session, owner, lifetime, read_only),
+            Command::LISTTOKENS() => self.list_tokens(session),
+            Command::REVOKETOKEN(id) => self.revoke_token(session, id),
+            Command::SCHEMA(collection, schema) => self.schema(session, collection, schema),
+            Command::LISTCOLLECTIONS() => self.list_collections(session),
+            Command::DROPCOLLECTION(name) => self.drop_collection(session, name),
+            Command::RENAMECOLLECTION(name, to) => self.rename_collection(session, name, to),
+        }
+    }
+
+    // Moves a collection file that couldn't be read into corrupt/ so the next save can't overwrite it
+    fn quarantine(file: &std::path::Path) -> Result<String, DatabaseError> {
+        let destination = Database::corrupt_path(file)?;
+        fs::rename(file, &destination)?;
+        Ok(destination.to_str().unwrap().to_string())
+    }
+
+    // Where in corrupt/ a file goes, numbered after any copies of it already there
+    fn corrupt_path(file: &std::path::Path) -> Result<std::path::PathBuf, DatabaseError> {
+        let dir = file.parent().unwrap().join("corrupt");
+        fs::create_dir_all(&dir)?;
+
+        let mut destination = dir.join(file.file_name().unwrap());
+        let mut copy = 1;
+        while destination.exists() {
+            destination = dir.join(format!("{}.{}", file.file_name().unwrap().to_str().unwrap(), copy));
+            copy += 1;
+        }
+        Ok(destination)
+    }
+
+    // Applies everything logged since the collection files were last written. Entries go straight
+    // into the collections as they were already permission checked when they were logged, and any
+    // a collection's snapshot already holds are skipped in case a save was cut short
+    fn replay_wal(&mut self, snapshot_lsns: &HashMap<String, u64>, mut unreadable: HashSet<String>) -> Result<(), DatabaseError> {
+        let replay = self.wal_manager.read_wal_log()?;
+        if let Some((offset, len)) = replay.torn_tail {
+            eprintln!("wal.log: discarded {} bytes of an incomplete record at byte {}", len, offset);
+        }
+        if let Some((offset, writes)) = replay.uncommitted {
+            eprintln!("wal.log: discarded a transaction of {} writes at byte {} that never committed", writes, offset);
+        }
+
+        let mut skipped = 0;
+        for (lsn, entry) in replay.entries {
+            if lsn <= snapshot_lsns.get(&entry.collection).copied().unwrap_or(0) {
+                continue
             }
+            // applying these would make the collection again out of nothing but its recent writes
+            if unreadable.contains(&entry.collection) {
+                match entry.operation.as_str() {
+                    "RENAME COLLECTION" => {
+                        unreadable.remove(&entry.collection);
+                        unreadable.insert(entry.key);
+                    }
+                    "DROP COLLECTION" => { unreadable.remove(&entry.collection); }
+                    _ => skipped += 1,
+                }
+                continue
+            }
+            self.apply_entry(entry)?;
+        }
+        if skipped > 0 {
+            eprintln!("wal.log: skipped {} writes to collections that couldn't be loaded", skipped);
+            // the next checkpoint empties the WAL, so keep the writes alongside the quarantined
+            // files. Read-only never checkpoints and leaves them where they are
+            if !self.read_only {
+                let wal = std::path::Path::new(&self.path).join("wal.log");
+                let destination = Database::corrupt_path(&wal)?;
+                fs::copy(&wal, &destination)?;
+                eprintln!("wal.log: copied to {}", destination.to_str().unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    // Applies a logged write to its collection, creating the collection if this is the first
+    // anyone has heard of it
+    fn apply_entry(&mut self, entry: WALEntry) -> Result<(), DatabaseError> {
+        // these mustn't bring back a collection that's already gone, the key holds the new name
+        match (entry.operation.as_str(), self.find_collection_by_name(&entry.collection)) {
+            ("DROP COLLECTION" | "RENAME COLLECTION", None) => return Ok(()),
+            ("DROP COLLECTION", Some(id)) => {
+                self.remove_collection(id);
+                return Ok(())
+            }
+            ("RENAME COLLECTION", Some(id)) => {
+                // a snapshot under the new name is from a checkpoint after the rename that got cut short
+                match self.find_collection_by_name(&entry.key) {
+                    Some(_) => self.remove_collection(id),
+                    None => {
+                        self.names.remove(&entry.collection);
+                        self.names.insert(entry.key.clone(), id);
+                        self.collection_mut(id).name = entry.key;
+                    }
+                }
+                return Ok(())
+            }
+            _ => (),
+        }
+
+        let id = match self.find_collection_by_name(&entry.collection) {
+            Some(id) => id,
+            None => self.add_collection(Collection::new(entry.collection.clone())),
+        };
+        let collection = self.collection_mut(id);
+
+        match entry.operation.as_str() {
+            "INSERT" => {
+                let value = serde_json::from_str(entry.value.as_deref().unwrap_or("null"))?;
+                collection.insert(entry.key, value);
+            }
+            "DELETE" => {
+                collection.delete(entry.key);
+            }
+            // the key holds the indexed path
+            "CREATE INDEX" => {
+                collection.create_index(entry.key);
+            }
+            "DROP INDEX" => {
+                collection.drop_index(&entry.key);
+            }
+            // no value takes the schema off
+            "SCHEMA" => {
+                let schema = match entry.value {
+                    Some(schema) => Some(Schema::parse(serde_json::from_str(&schema)?)?),
+                    None => None,
+                };
+                collection.set_schema(schema);
+            }
+            operation => return Err(DatabaseError::SerializationError(format!("unknown WAL operation {}", operation))),
         }
+        Ok(())
+    }
+}
+
+pub struct CollectionHandle<'a> {
+    database: &'a mut Database,
+    id: CollectionId,
+}
+
+impl CollectionHandle<'_> {
+    // Runs an operation through the same checks and WAL logging as the command language, with
+    // this collection selected for just that call
+    fn run<T>(&mut self, operation: impl FnOnce(&mut Database, &mut Session) -> Result<T, DatabaseError>) -> Result<T, DatabaseError> {
+        let mut session = self.database.current_session.take()
+            .ok_or(DatabaseError::UserError("Login to access the database".to_string()))?;
+        let selected = std::mem::replace(&mut session.state, DatabaseState::SelectedCollection(self.id));
+        let result = operation(self.database, &mut session);
+        session.state = selected;
+        self.database.current_session = Some(session);
+        result
+    }
 
-        // clear the WAL 
-        let mut wal = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(format!("{}/wal.log", &self.path))?;
+    // Named with the collection so a key with a dot in it can't be taken for another one
+    fn key(&self, key: &str) -> Key {
+        Key::In(self.database.collections[&self.id].name.clone(), key.to_string())
+    }
+
+    pub fn get<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, DatabaseError> {
+        let qualified = self.key(key);
+        match self.run(|database, session| database.execute(session, Command::GET(qualified))) {
+            Ok(Response::Value(value)) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(|e| DatabaseError::SerializationError(format!("{}: {}", key, e))),
+            Ok(Response::Message(_)) | Err(DatabaseError::ValueNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-        wal.flush()?;
-        std::mem::drop(wal);
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), DatabaseError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| DatabaseError::SerializationError(format!("{}: {}", key, e)))?;
+        let qualified = self.key(key);
+        self.run(|database, session| database.execute(session, Command::INSERT(qualified, value)))?;
         Ok(())
     }
+
+    // The document after the update
+    pub fn update(&mut self, key: &str, patch: Value) -> Result<Value, DatabaseError> {
+        let update = Update::parse(patch)?;
+        let qualified = self.key(key);
+        match self.run(|database, session| database.execute(session, Command::UPDATE(qualified, update)))? {
+            Response::Value(value) => Ok(value),
+            Response::Message(message) => Err(DatabaseError::Other(message)),
+        }
+    }
+
+    // What was stored under the key, if anything was
+    pub fn delete(&mut self, key: &str) -> Result<Option<Value>, DatabaseError> {
+        let qualified = self.key(key);
+        match self.run(|database, session| database.execute(session, Command::DELETE(qualified))) {
+            Ok(Response::Value(value)) => Ok(Some(value)),
+            Ok(Response::Message(_)) | Err(DatabaseError::ValueNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Keys and values in key order, read lazily so nothing is copied until it is asked for
+    pub fn scan<'b>(&'b self, prefix: &'b str) -> Result<impl Iterator<Item = (String, Value)> + 'b, DatabaseError> {
+        self.can_read()?;
+        Ok(self.database.collections[&self.id].scan(prefix)
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    pub fn range<'b>(&'b self, from: &'b str, to: &'b str) -> Result<impl Iterator<Item = (String, Value)> + 'b, DatabaseError> {
+        self.can_read()?;
+        Ok(self.database.collections[&self.id].range(Bound::Included(from), Bound::Excluded(to))
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    fn can_read(&self) -> Result<(), DatabaseError> {
+        let session = self.database.current_session.as_ref()
+            .ok_or(DatabaseError::UserError("Login to access the database".to_string()))?;
+        self.database.authorize(session, Some(self.id), Access::Read)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use serde_json::{json, Value};
+    use tempdir::TempDir;
+
+    use crate::audit::AuditFilter;
+    use crate::auth::{Access, Grantee, Permissions};
+    use crate::crypto::KeySource;
+    use crate::database::{Database, DatabaseState, OpenOptions, RecoveryPolicy};
+    use crate::errors::DatabaseError;
+    use crate::parser::{Command, Key};
+    use crate::query::Query;
+    use crate::session::Session;
+    use crate::update::Update;
+
+    fn open(dir: &TempDir, recovery: RecoveryPolicy) -> Result<(Database, Session), DatabaseError> {
+        let path = dir.path().to_str().unwrap().to_string();
+        let mut database = match dir.path().join("users.log").exists() {
+            true => Database::open(path, OpenOptions { recovery, ..Default::default() })?,
+            false => Database::init(path, &"admin".to_string(), &"password".to_string())?,
+        };
+        let session = database.authenticate("admin".to_string(), "password".to_string())?;
+        database.current_session = Some(session.clone());
+        Ok((database, session))
+    }
+
+    // two collections saved to disk, then one of their files damaged
+    fn corrupted(dir: &TempDir) {
+        let (mut database, mut session) = open(dir, RecoveryPolicy::Fail).unwrap();
+        for name in ["good", "bad"] {
+            database.new_collection(&mut session, &name.to_string()).unwrap();
+            database.select(&mut session, name.to_string()).unwrap();
+            database.insert(&mut session, "key".into(), json!(1)).unwrap();
+        }
+        database.save_data().unwrap();
+
+        let path = dir.path().join("bad.db");
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 3]).unwrap();
+    }
+
+    #[test]
+    fn reopen_replays_unsaved_writes() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.new_collection(&mut session, &"test".to_string()).unwrap();
+        database.select(&mut session, "test".to_string()).unwrap();
+        database.insert(&mut session, "a".into(), json!({"b": 1})).unwrap();
+        database.save_data().unwrap();
+        database.insert(&mut session, "c".into(), json!(2)).unwrap();
+        std::mem::drop(database);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.select(&mut session, "test".to_string()).unwrap();
+        assert!(matches!(database.get(&session, "a".into()), Ok(super::Response::Value(v)) if v == json!({"b": 1})));
+        assert!(matches!(database.get(&session, "c".into()), Ok(super::Response::Value(v)) if v == json!(2)));
+    }
+
+    #[test]
+    fn transaction_commits_or_rolls_back_together() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.new_collection(&mut session, &"test".to_string()).unwrap();
+        database.select(&mut session, "test".to_string()).unwrap();
+        database.insert(&mut session, "from".into(), json!(10)).unwrap();
+
+        database.begin(&mut session).unwrap();
+        database.delete(&mut session, "from".into()).unwrap();
+        database.insert(&mut session, "to".into(), json!(10)).unwrap();
+        assert!(database.get(&session, "from".into()).is_err());
+        database.rollback(&mut session).unwrap();
+        assert!(database.get(&session, "from".into()).is_ok());
+        assert!(database.get(&session, "to".into()).is_err());
+
+        database.begin(&mut session).unwrap();
+        database.delete(&mut session, "from".into()).unwrap();
+        database.insert(&mut session, "to".into(), json!(10)).unwrap();
+        database.commit(&mut session).unwrap();
+        std::mem::drop(database);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.select(&mut session, "test".to_string()).unwrap();
+        assert!(database.get(&session, "from".into()).is_err());
+        assert!(database.get(&session, "to".into()).is_ok());
+    }
+
+    #[test]
+    fn commit_checks_writes_against_the_schema_they_meet() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        let mut other = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.new_collection(&mut session, &"test".to_string()).unwrap();
+        database.select(&mut session, "test".to_string()).unwrap();
+
+        database.begin(&mut session).unwrap();
+        database.insert(&mut session, "a".into(), json!({"n": 1})).unwrap();
+        database.insert(&mut session, "b".into(), json!({"n": "one"})).unwrap();
+        database.schema(&mut other, "test".to_string(), Some(json!({"properties": {"n": {"type": "number"}}}))).unwrap();
+        assert!(matches!(database.commit(&mut session), Err(DatabaseError::ValidationError { path, .. }) if path == "b at $.n"));
+        assert!(session.transaction.is_none());
+        assert!(database.get(&session, "a".into()).is_err());
+    }
+
+    #[test]
+    fn commit_needs_write_access_when_it_happens() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut admin) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.create_user(&admin, "alice".to_string(), "password".to_string(), Permissions::Guest()).unwrap();
+        let mut alice = database.authenticate("alice".to_string(), "password".to_string()).unwrap();
+        database.new_collection(&mut admin, &"test".to_string()).unwrap();
+        database.grant(&mut admin, Access::Write, "test".to_string(), Grantee::User("alice".to_string())).unwrap();
+        database.select(&mut alice, "test".to_string()).unwrap();
+
+        database.begin(&mut alice).unwrap();
+        database.insert(&mut alice, "a".into(), json!(1)).unwrap();
+        database.revoke(&mut admin, Access::Write, "test".to_string(), Grantee::User("alice".to_string())).unwrap();
+        assert!(matches!(database.commit(&mut alice), Err(DatabaseError::PermissionDenied(_))));
+        assert!(database.get(&alice, "a".into()).is_err());
+
+        // the same for a user who's demoted in the meantime
+        database.alter_role(&admin, "alice".to_string(), Permissions::User()).unwrap();
+        database.begin(&mut alice).unwrap();
+        database.insert(&mut alice, "a".into(), json!(1)).unwrap();
+        database.alter_role(&admin, "alice".to_string(), Permissions::Guest()).unwrap();
+        assert!(matches!(database.commit(&mut alice), Err(DatabaseError::PermissionDenied(_))));
+        assert!(database.get(&alice, "a".into()).is_err());
+    }
+
+    #[test]
+    fn typed_collection_handle() {
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct User {
+            name: String,
+            age: u32,
+        }
+
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.new_collection(&mut session, &"users".to_string()).unwrap();
+        database.new_collection(&mut session, &"other".to_string()).unwrap();
+        database.operate_db(crate::parser::Command::SELECT("other".to_string())).unwrap();
+
+        let alice = User { name: "alice".to_string(), age: 30 };
+        let mut users = database.collection("users").unwrap();
+        users.insert("alice", &alice).unwrap();
+        assert_eq!(users.get::<User>("alice").unwrap(), Some(alice));
+        assert_eq!(users.get::<User>("bob").unwrap(), None);
+        assert!(users.get::<String>("alice").is_err());
+        assert_eq!(users.delete("alice").unwrap(), Some(json!({"name": "alice", "age": 30})));
+        assert!(database.collection("missing").is_err());
+
+        // the REPL selection is left where it was
+        let which = database.operate_db(crate::parser::Command::WHICH("collection".to_string())).unwrap();
+        assert!(matches!(which, super::Response::Message(message) if message == "other selected"));
+    }
+
+    #[test]
+    fn indexes_survive_restart() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.new_collection(&mut session, &"users".to_string()).unwrap();
+        database.select(&mut session, "users".to_string()).unwrap();
+        database.insert(&mut session, "a".into(), json!({"email": "a@x"})).unwrap();
+        database.create_index(&mut session, "users".to_string(), "email".to_string()).unwrap();
+        assert!(database.create_index(&mut session, "users".to_string(), "email".to_string()).is_err());
+        database.save_data().unwrap();
+        database.insert(&mut session, "b".into(), json!({"email": "b@x"})).unwrap();
+        std::mem::drop(database);
+
+        // the index comes back from the snapshot and picks up b from the WAL
+        let (database, session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        assert_eq!(database.collections.values().next().unwrap().index_paths(), vec!["email"]);
+        let found = database.find(&session, Query::parse("users WHERE email = \"b@x\"").unwrap()).unwrap();
+        assert!(matches!(found, super::Response::Value(v) if v == json!([{"key": "b", "value": {"email": "b@x"}}])));
+    }
+
+    #[test]
+    fn scans_page_through_keys_in_order() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.new_collection(&mut session, &"orders".to_string()).unwrap();
+        database.select(&mut session, "orders".to_string()).unwrap();
+        for key in ["order:2024:0003", "order:2023:0001", "order:2024:0001", "order:2024:0002", "order:2025:0001"] {
+            database.insert(&mut session, key.into(), json!(key)).unwrap();
+        }
+
+        let page = |database: &Database, session: &Session, after: Option<&str>| match database.scan(session, "order:2024:".into(), Some(2), after.map(|a| a.to_string())) {
+            Ok(super::Response::Value(value)) => value,
+            other => panic!("expected a page, got {:?}", other),
+        };
+        let first = page(&database, &session, None);
+        assert_eq!(first["items"], json!([{"key": "order:2024:0001", "value": "order:2024:0001"}, {"key": "order:2024:0002", "value": "order:2024:0002"}]));
+        assert_eq!(first["cursor"], json!("order:2024:0002"));
+        let second = page(&database, &session, first["cursor"].as_str());
+        assert_eq!(second["items"], json!([{"key": "order:2024:0003", "value": "order:2024:0003"}]));
+        assert_eq!(second["cursor"], json!(null));
+
+        match database.range(&session, "order:2023".into(), "order:2024:0002".into(), None, None) {
+            Ok(super::Response::Value(value)) => assert_eq!(value["items"].as_array().unwrap().len(), 2),
+            other => panic!("expected a page, got {:?}", other),
+        }
+
+        let handle = database.collection("orders").unwrap();
+        let keys: Vec<String> = handle.scan("order:2025").unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["order:2025:0001"]);
+    }
+
+    #[test]
+    fn grants_limit_what_a_user_sees() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut admin) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        for name in ["orders", "payroll"] {
+            database.new_collection(&mut admin, &name.to_string()).unwrap();
+            database.select(&mut admin, name.to_string()).unwrap();
+            database.insert(&mut admin, "a".into(), json!(1)).unwrap();
+        }
+        database.create_user(&admin, "contractor".to_string(), "password".to_string(), Permissions::Restricted()).unwrap();
+        database.grant(&mut admin, Access::Write, "orders".to_string(), Grantee::User("contractor".to_string())).unwrap();
+        database.revoke(&mut admin, Access::Write, "orders".to_string(), Grantee::User("contractor".to_string())).unwrap();
+        std::mem::drop(database);
+
+        // the grant outlives a restart and revoking write left read behind
+        let (mut database, _) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        let mut contractor = database.authenticate("contractor".to_string(), "password".to_string()).unwrap();
+        assert!(matches!(database.select(&mut contractor, "payroll".to_string()), Err(DatabaseError::PermissionDenied(_))));
+        database.select(&mut contractor, "orders".to_string()).unwrap();
+        assert!(database.get(&contractor, "a".into()).is_ok());
+        assert!(matches!(database.insert(&mut contractor, "b".into(), json!(2)), Err(DatabaseError::PermissionDenied(_))));
+        assert!(matches!(database.new_collection(&mut contractor, &"mine".to_string()), Err(DatabaseError::PermissionDenied(_))));
+        assert!(matches!(database.grant(&mut contractor, Access::Read, "payroll".to_string(), Grantee::User("contractor".to_string())),
+            Err(DatabaseError::PermissionDenied(_))));
+
+        // grants to a role reach everyone with it
+        let mut admin = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.grant(&mut admin, Access::Write, "orders".to_string(), Grantee::Role(Permissions::Restricted())).unwrap();
+        assert!(database.insert(&mut contractor, "b".into(), json!(2)).is_ok());
+    }
+
+    #[test]
+    fn admins_manage_users() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, admin) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.create_user(&admin, "alice".to_string(), "first".to_string(), Permissions::User()).unwrap();
+        let alice = database.authenticate("alice".to_string(), "first".to_string()).unwrap();
+        assert!(matches!(database.list_users(&alice), Err(DatabaseError::PermissionDenied(_))));
+
+        database.alter_password(&admin, "alice".to_string(), "second".to_string()).unwrap();
+        database.alter_role(&admin, "alice".to_string(), Permissions::Admin()).unwrap();
+        // the role change reaches the session alice already had
+        assert!(database.list_users(&alice).is_ok());
+        // admin can go now that alice is an admin too, but alice is then the last one
+        database.drop_user(&alice, "admin".to_string()).unwrap();
+        assert!(database.alter_role(&alice, "alice".to_string(), Permissions::User()).is_err());
+        assert!(database.drop_user(&alice, "alice".to_string()).is_err());
+        std::mem::drop(database);
+
+        let mut database = Database::open(dir.path().to_str().unwrap().to_string(), OpenOptions::default()).unwrap();
+        assert!(database.authenticate("alice".to_string(), "first".to_string()).is_err());
+        let alice = database.authenticate("alice".to_string(), "second".to_string()).unwrap();
+        match database.list_users(&alice) {
+            Ok(super::Response::Value(users)) => assert_eq!(users, json!([{"username": "alice", "role": "admin", "failed_attempts": 0, "locked_until": null}])),
+            other => panic!("expected users, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_only_tokens_cannot_write() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut admin) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.execute(&mut admin, Command::NEW("builds".to_string())).unwrap();
+        database.create_user(&admin, "ci".to_string(), "password".to_string(), Permissions::User()).unwrap();
+        let token = match database.create_token(&admin, Some("ci".to_string()), None, true) {
+            Ok(super::Response::Value(created)) => created,
+            other => panic!("expected a token, got {:?}", other),
+        };
+        std::mem::drop(database);
+
+        let mut database = Database::open(dir.path().to_str().unwrap().to_string(), OpenOptions::default()).unwrap();
+        let mut ci = database.authenticate("ci".to_string(), token["token"].as_str().unwrap().to_string()).unwrap();
+        database.execute(&mut ci, Command::SELECT("builds".to_string())).unwrap();
+        assert!(database.execute(&mut ci, Command::GET("latest".into())).is_err_and(|e| matches!(e, DatabaseError::ValueNotFound(_))));
+        assert!(matches!(database.execute(&mut ci, Command::INSERT("latest".into(), json!(1))), Err(DatabaseError::PermissionDenied(_))));
+        // and it can't mint itself a writable one
+        assert!(matches!(database.create_token(&ci, None, None, false), Err(DatabaseError::PermissionDenied(_))));
+
+        let admin = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.revoke_token(&admin, token["id"].as_str().unwrap().to_string()).unwrap();
+        assert!(database.authenticate("ci".to_string(), token["token"].as_str().unwrap().to_string()).is_err());
+        assert!(database.authenticate_token(token["token"].as_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn encrypted_files_need_the_key() {
+        let dir = TempDir::new("database").unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        let key = |name: &str, hex: &str| {
+            fs::write(dir.path().join(name), hex).unwrap();
+            KeySource::KeyFile(dir.path().join(name).to_str().unwrap().to_string())
+        };
+        let (first, second) = (key("first.key", &"1f".repeat(32)), key("second.key", &"2e".repeat(32)));
+        let with = |key: &KeySource| OpenOptions { key: Some(key.clone()), ..Default::default() };
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.execute(&mut session, Command::NEW("people".to_string())).unwrap();
+        database.execute(&mut session, Command::SELECT("people".to_string())).unwrap();
+        database.execute(&mut session, Command::INSERT("alice".into(), json!({"email": "alice@example.com"}))).unwrap();
+        database.rekey(Some(&first)).unwrap();
+        // written after the rekey so it's only in the WAL
+        database.execute(&mut session, Command::INSERT("bob".into(), json!({"email": "bob@example.com"}))).unwrap();
+        // the failure goes into audit.log along with the value that didn't fit
+        let schema = json!({"properties": {"email": {"type": "string", "pattern": "@"}}});
+        database.execute(&mut session, Command::SCHEMA("people".to_string(), Some(schema))).unwrap();
+        assert!(database.execute(&mut session, Command::INSERT("carol".into(), json!({"email": "carol.example"}))).is_err());
+        std::mem::drop(database);
+
+        for file in ["people.db", "users.log", "wal.log", "audit.log"] {
+            let contents = fs::read(dir.path().join(file)).unwrap();
+            assert!(!contents.windows(7).any(|window| window == b"example" || window == b"admin\0\0"), "{} is readable", file);
+            assert!(!contents.windows(5).any(|window| window == b"alice" || window == b"carol"), "{} is readable", file);
+        }
+        assert!(matches!(Database::open(path.clone(), OpenOptions::default()), Err(DatabaseError::EncryptionError(_))));
+        assert!(matches!(Database::open(path.clone(), with(&second)), Err(DatabaseError::EncryptionError(_))));
+
+        let mut database = Database::open(path.clone(), with(&first)).unwrap();
+        database.rekey(Some(&second)).unwrap();
+        std::mem::drop(database);
+        assert!(Database::open(path.clone(), with(&first)).is_err());
+
+        // a rekey that died before the new key was switched to leaves files that are thrown away
+        fs::write(dir.path().join("people.db.rekey"), b"half written").unwrap();
+        let mut database = Database::open(path.clone(), with(&second)).unwrap();
+        assert!(!dir.path().join("people.db.rekey").exists());
+        let mut session = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.execute(&mut session, Command::SELECT("people".to_string())).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("bob".into())), Ok(super::Response::Value(bob)) if bob["email"] == "bob@example.com"));
+
+        database.rekey(None).unwrap();
+        std::mem::drop(database);
+        let database = Database::open(path, OpenOptions::default()).unwrap();
+        let filter = AuditFilter { key: Some("carol".to_string()), ..Default::default() };
+        let Ok(super::Response::Value(entries)) = database.audit_entries(&session, filter) else { panic!("expected entries") };
+        assert!(entries[0]["error"].as_str().unwrap().contains("carol.example"));
+    }
+
+    #[test]
+    fn schema_rejects_malformed_documents() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.execute(&mut session, Command::NEW("users".to_string())).unwrap();
+        database.execute(&mut session, Command::SELECT("users".to_string())).unwrap();
+        database.execute(&mut session, Command::INSERT("old".into(), json!({"name": "no email"}))).unwrap();
+
+        let schema = json!({"type": "object", "required": ["email"], "properties": {"email": {"type": "string"}}});
+        // the document already there doesn't fit
+        assert!(matches!(database.execute(&mut session, Command::SCHEMA("users".to_string(), Some(schema.clone()))),
+            Err(DatabaseError::ValidationError { path, .. }) if path == "old at $.email"));
+        database.execute(&mut session, Command::DELETE("old".into())).unwrap();
+        database.execute(&mut session, Command::SCHEMA("users".to_string(), Some(schema.clone()))).unwrap();
+
+        assert!(matches!(database.execute(&mut session, Command::INSERT("a".into(), json!({"email": 5}))),
+            Err(DatabaseError::ValidationError { path, .. }) if path == "$.email"));
+        database.execute(&mut session, Command::INSERT("a".into(), json!({"email": "a@x"}))).unwrap();
+        std::mem::drop(database);
+
+        // through the WAL and then through a snapshot
+        for _ in 0..2 {
+            let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+            assert!(matches!(database.execute(&mut session, Command::SCHEMA("users".to_string(), None)), Ok(super::Response::Value(found)) if found == schema));
+            database.execute(&mut session, Command::SELECT("users".to_string())).unwrap();
+            assert!(database.execute(&mut session, Command::INSERT("b".into(), json!({}))).is_err());
+            database.checkpoint().unwrap();
+        }
+    }
+
+    #[test]
+    fn collections_are_listed_renamed_and_dropped() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        for name in ["orders", "carts"] {
+            database.execute(&mut session, Command::NEW(name.to_string())).unwrap();
+            database.execute(&mut session, Command::SELECT(name.to_string())).unwrap();
+            database.execute(&mut session, Command::INSERT("a".into(), json!(1))).unwrap();
+        }
+        database.execute(&mut session, Command::INSERT("b".into(), json!(2))).unwrap();
+
+        for name in ["orders", "../../etc/x", "a/b", "a.b", ""] {
+            assert!(matches!(database.execute(&mut session, Command::NEW(name.to_string())), Err(DatabaseError::CollectionError(_))));
+        }
+        assert!(!dir.path().join("a").exists());
+
+        database.checkpoint().unwrap();
+        let stale = fs::read(dir.path().join("orders.db")).unwrap();
+        database.execute(&mut session, Command::CREATEUSER("contractor".to_string(), "password".to_string(), Permissions::Restricted())).unwrap();
+        database.execute(&mut session, Command::GRANT(Access::Read, "orders".to_string(), Grantee::User("contractor".to_string()))).unwrap();
+        assert!(database.execute(&mut session, Command::RENAMECOLLECTION("orders".to_string(), "carts".to_string())).is_err());
+        database.execute(&mut session, Command::RENAMECOLLECTION("orders".to_string(), "archive".to_string())).unwrap();
+        database.execute(&mut session, Command::DROPCOLLECTION("carts".to_string())).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("a".into())), Err(DatabaseError::CollectionError(_))));
+        assert!(!dir.path().join("orders.db").exists() && !dir.path().join("carts.db").exists());
+        std::mem::drop(database);
+
+        // as if the rename's checkpoint stopped before it removed the old file
+        fs::write(dir.path().join("orders.db"), stale).unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        match database.execute(&mut session, Command::LISTCOLLECTIONS()) {
+            Ok(super::Response::Value(Value::Array(listed))) => {
+                assert_eq!(listed.len(), 1);
+                assert_eq!((&listed[0]["name"], &listed[0]["keys"]), (&json!("archive"), &json!(1)));
+                assert!(listed[0]["bytes"].as_u64().unwrap() > 0);
+            }
+            other => panic!("expected a list, got {:?}", other),
+        }
+        // the grant went with the rename
+        let contractor = Session { user: "contractor".to_string(), permissions: Permissions::Restricted(), state: DatabaseState::Unselected(), transaction: None, read_only: false };
+        assert_eq!(database.auth_manager.access(&contractor, Some("archive")), Some(Access::Read));
+        assert_eq!(database.auth_manager.access(&contractor, Some("orders")), None);
+    }
+
+    #[test]
+    fn selection_follows_its_collection() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        for name in ["orders", "carts", "users"] {
+            database.execute(&mut session, Command::NEW(name.to_string())).unwrap();
+        }
+        database.execute(&mut session, Command::SELECT("users".to_string())).unwrap();
+        database.execute(&mut session, Command::INSERT("alice".into(), json!(1))).unwrap();
+
+        // keys can name their collection instead of it being selected, a dot that doesn't start
+        // with a collection name is just part of the key
+        database.execute(&mut session, Command::INSERT("orders.o:1".into(), json!({"total": 5}))).unwrap();
+        database.execute(&mut session, Command::INSERT("carts.c.1".into(), json!(2))).unwrap();
+        database.execute(&mut session, Command::INSERT("bob.smith".into(), json!(3))).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::UPDATE("orders.o:1".into(), Update::parse(json!({"$inc": {"total": 1}})).unwrap())),
+            Ok(super::Response::Value(value)) if value == json!({"total": 6})));
+        assert!(matches!(database.execute(&mut session, Command::GET("carts.c.1".into())), Ok(super::Response::Value(value)) if value == json!(2)));
+        assert!(matches!(database.execute(&mut session, Command::SCAN("orders.o:".into(), None, None)),
+            Ok(super::Response::Value(page)) if page["items"][0]["key"] == "o:1"));
+        assert!(matches!(database.execute(&mut session, Command::WHICH("collection".to_string())), Ok(super::Response::Message(m)) if m == "users selected"));
+
+        // dropping a collection before it doesn't move the selection, renaming it keeps it
+        database.execute(&mut session, Command::DROPCOLLECTION("carts".to_string())).unwrap();
+        database.execute(&mut session, Command::RENAMECOLLECTION("users".to_string(), "people".to_string())).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("bob.smith".into())), Ok(super::Response::Value(value)) if value == json!(3)));
+
+        // once a collection takes the name before the dot the bare key goes there, quoted it's
+        // still the one in the selected collection
+        database.execute(&mut session, Command::NEW("bob".to_string())).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("bob.smith".into())), Err(DatabaseError::ValueNotFound(_))));
+        let quoted = || Key::Quoted("bob.smith".to_string());
+        assert!(matches!(database.execute(&mut session, Command::GET(quoted())), Ok(super::Response::Value(value)) if value == json!(3)));
+        database.execute(&mut session, Command::UPDATE(quoted(), Update::Merge(json!(5)))).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::DELETE(quoted())), Ok(super::Response::Value(value)) if value == json!(5)));
+
+        // and a collection made under a dropped one's name isn't the one that was selected
+        database.execute(&mut session, Command::DROPCOLLECTION("people".to_string())).unwrap();
+        database.execute(&mut session, Command::NEW("people".to_string())).unwrap();
+        database.execute(&mut session, Command::INSERT("people.carol".into(), json!(4))).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("carol".into())), Err(DatabaseError::CollectionError(_))));
+        std::mem::drop(database);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("orders.o:1".into())), Ok(super::Response::Value(value)) if value == json!({"total": 6})));
+        assert!(matches!(database.execute(&mut session, Command::GET("people.carol".into())), Ok(super::Response::Value(value)) if value == json!(4)));
+    }
+
+    #[test]
+    fn updates_replay_as_their_result() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.execute(&mut session, Command::NEW("pages".to_string())).unwrap();
+        database.execute(&mut session, Command::SELECT("pages".to_string())).unwrap();
+        database.execute(&mut session, Command::INSERT("home".into(), json!({"hits": 1, "tags": ["a"]}))).unwrap();
+
+        let increment = || Command::UPDATE("home".into(), Update::parse(json!({"$inc": {"hits": 1}, "$push": {"tags": "b"}})).unwrap());
+        database.execute(&mut session, increment()).unwrap();
+        // a transaction updates what it already wrote
+        database.execute(&mut session, Command::BEGIN()).unwrap();
+        database.execute(&mut session, increment()).unwrap();
+        assert!(matches!(database.execute(&mut session, increment()), Ok(super::Response::Value(page)) if page["hits"] == 4));
+        database.execute(&mut session, Command::COMMIT()).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::UPDATE("nothing".into(), Update::Merge(json!({})))),
+            Err(DatabaseError::ValueNotFound(_))));
+        std::mem::drop(database);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.execute(&mut session, Command::SELECT("pages".to_string())).unwrap();
+        assert!(matches!(database.execute(&mut session, Command::GET("home".into())),
+            Ok(super::Response::Value(page)) if page == json!({"hits": 4, "tags": ["a", "b", "b", "b"]})));
+    }
+
+    #[test]
+    fn init_creates_the_first_admin() {
+        let dir = TempDir::new("database").unwrap();
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        assert!(Database::open(path.clone(), OpenOptions::default()).is_err());
+
+        let mut database = Database::init(path.clone(), &"root".to_string(), &"password".to_string()).unwrap();
+        let root = database.authenticate("root".to_string(), "password".to_string()).unwrap();
+        database.create_user(&root, "alice".to_string(), "password".to_string(), Permissions::User()).unwrap();
+        let alice = database.authenticate("alice".to_string(), "password".to_string()).unwrap();
+        assert!(matches!(database.create_user(&alice, "bob".to_string(), "password".to_string(), Permissions::User()),
+            Err(DatabaseError::PermissionDenied(_))));
+        std::mem::drop(database);
+
+        assert!(Database::init(path.clone(), &"other".to_string(), &"password".to_string()).is_err());
+        assert!(Database::open(path, OpenOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn audit_log_says_who_deleted_a_key() {
+        let dir = TempDir::new("database").unwrap();
+        let (mut database, mut admin) = open(&dir, RecoveryPolicy::Fail).unwrap();
+        database.create_user(&admin, "alice".to_string(), "password".to_string(), Permissions::User()).unwrap();
+        assert!(database.authenticate("alice".to_string(), "wrong".to_string()).is_err());
+        let mut alice = database.authenticate("alice".to_string(), "password".to_string()).unwrap();
+
+        database.execute(&mut admin, Command::NEW("orders".to_string())).unwrap();
+        database.execute(&mut alice, Command::SELECT("orders".to_string())).unwrap();
+        database.execute(&mut alice, Command::INSERT("a".into(), json!(1))).unwrap();
+        database.execute(&mut alice, Command::DELETE("a".into())).unwrap();
+        assert!(database.execute(&mut alice, Command::DELETE("a".into())).is_err());
+        assert!(matches!(database.execute(&mut alice, Command::AUDIT(AuditFilter::default())), Err(DatabaseError::PermissionDenied(_))));
+
+        let filter = AuditFilter { operation: Some("DELETE".to_string()), key: Some("a".to_string()), ..Default::default() };
+        let Ok(super::Response::Value(entries)) = database.execute(&mut admin, Command::AUDIT(filter)) else { panic!("expected entries") };
+        let entries = entries.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((&entries[0]["user"], &entries[0]["collection"], &entries[0]["success"]), (&json!("alice"), &json!("orders"), &json!(true)));
+        assert_eq!(entries[1]["success"], json!(false));
+
+        // a qualified key goes down as the collection it named, not the selected one
+        database.execute(&mut admin, Command::NEW("archive".to_string())).unwrap();
+        database.execute(&mut alice, Command::INSERT("archive.b".into(), json!(2))).unwrap();
+        database.execute(&mut alice, Command::RANGE("archive.a".into(), "archive.c".into(), None, None)).unwrap();
+        let filter = AuditFilter { collection: Some("archive".to_string()), ..Default::default() };
+        let Ok(super::Response::Value(entries)) = database.audit_entries(&admin, filter) else { panic!("expected entries") };
+        let entries: Vec<_> = entries.as_array().unwrap().iter().map(|entry| (entry["operation"].clone(), entry["key"].clone())).collect();
+        assert_eq!(entries, vec![(json!("NEW"), json!(null)), (json!("INSERT"), json!("b")), (json!("RANGE"), json!("a..c"))]);
+
+        let filter = AuditFilter { user: Some("alice".to_string()), operation: Some("LOGIN".to_string()), ..Default::default() };
+        let Ok(super::Response::Value(logins)) = database.audit_entries(&admin, filter) else { panic!("expected entries") };
+        assert_eq!(logins.as_array().unwrap().iter().map(|login| login["success"].clone()).collect::<Vec<_>>(), vec![json!(false), json!(true)]);
+    }
+
+    #[test]
+    fn writes_to_a_corrupt_collection_are_not_replayed_on_their_own() {
+        for recovery in [RecoveryPolicy::Quarantine, RecoveryPolicy::ReadOnly] {
+            let dir = TempDir::new("database").unwrap();
+            let (mut database, mut session) = open(&dir, RecoveryPolicy::Fail).unwrap();
+            for name in ["good", "bad"] {
+                database.new_collection(&mut session, &name.to_string()).unwrap();
+                database.select(&mut session, name.to_string()).unwrap();
+                database.insert(&mut session, "key".into(), json!(1)).unwrap();
+            }
+            database.save_data().unwrap();
+            // logged after the snapshot that's about to be damaged
+            database.insert(&mut session, "later".into(), json!(2)).unwrap();
+            std::mem::drop(database);
+            let path = dir.path().join("bad.db");
+            let contents = fs::read(&path).unwrap();
+            fs::write(&path, &contents[..contents.len() - 3]).unwrap();
+
+            let (mut database, mut session) = open(&dir, recovery).unwrap();
+            assert!(matches!(database.select(&mut session, "bad".to_string()), Err(DatabaseError::CollectionNotFound(_))));
+            database.select(&mut session, "good".to_string()).unwrap();
+            assert!(database.get(&session, "key".into()).is_ok());
+            // read-only keeps the writes in the WAL for when the file is fixed, quarantine keeps a
+            // copy of it that the next checkpoint can't empty
+            match recovery {
+                RecoveryPolicy::ReadOnly => {
+                    assert!(fs::metadata(dir.path().join("wal.log")).unwrap().len() > 0);
+                    assert!(!dir.path().join("corrupt").join("wal.log").exists());
+                }
+                _ => {
+                    database.save_data().unwrap();
+                    let copy = fs::read(dir.path().join("corrupt").join("wal.log")).unwrap();
+                    assert!(copy.windows(5).any(|window| window == b"later"));
+                    assert_eq!(fs::metadata(dir.path().join("wal.log")).unwrap().len(), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_collection_fails_to_open() {
+        let dir = TempDir::new("database").unwrap();
+        corrupted(&dir);
+
+        match open(&dir, RecoveryPolicy::Fail) {
+            Err(DatabaseError::CorruptData { file, .. }) => assert!(file.ends_with("bad.db")),
+            other => panic!("expected corruption error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn corrupt_collection_is_quarantined() {
+        let dir = TempDir::new("database").unwrap();
+        corrupted(&dir);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::Quarantine).unwrap();
+        assert!(dir.path().join("corrupt/bad.db").exists());
+        assert!(database.select(&mut session, "bad".to_string()).is_err());
+        database.select(&mut session, "good".to_string()).unwrap();
+        database.insert(&mut session, "other".into(), json!(2)).unwrap();
+    }
+
+    #[test]
+    fn corrupt_collection_opens_read_only() {
+        let dir = TempDir::new("database").unwrap();
+        corrupted(&dir);
+
+        let (mut database, mut session) = open(&dir, RecoveryPolicy::ReadOnly).unwrap();
+        database.select(&mut session, "good".to_string()).unwrap();
+        assert!(database.get(&session, "key".into()).is_ok());
+        assert!(matches!(database.insert(&mut session, "key".into(), json!(2)), Err(DatabaseError::PermissionDenied(_))));
+        assert!(database.save_data().is_err());
+        assert!(dir.path().join("bad.db").exists());
+    }
+}
@@ -1,3 +1,5 @@
+use serde_json::{json, Value};
+
 use std::fmt;
 use std::io;
 
@@ -5,13 +7,21 @@ use std::io;
 #[derive(Debug)]
 pub enum DatabaseError {
     ValueNotFound(String),
-    SyntaxError(String),
+    // column counts characters from the start of the command, from 1, and expected is what would
+    // have fitted there. Both are None for mistakes that aren't at one place in a command, like a
+    // schema with an unknown keyword
+    SyntaxError { message: String, column: Option<usize>, expected: Option<String> },
     PermissionDenied(String),
     CollectionNotFound(String),
     UserError(String),
     SerializationError(String),
     IOError(io::Error),
     CollectionError(String),
+    CorruptData { file: String, offset: u64, reason: String },
+    // a document that doesn't fit its collection's schema, path is where in the document
+    ValidationError { path: String, reason: String },
+    // a wrong or missing key, or a key given for a database that isn't encrypted
+    EncryptionError(String),
     Other(String),
 }
 
@@ -19,18 +29,70 @@ impl fmt::Display for DatabaseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DatabaseError::ValueNotFound(msg) => write!(f, "Value not found: {}", msg),
-            DatabaseError::SyntaxError(msg) => write!(f, "Syntax Error: {}", msg),
+            DatabaseError::SyntaxError { message, column, expected } => {
+                write!(f, "Syntax Error")?;
+                if let Some(column) = column {
+                    write!(f, " at column {}", column)?;
+                }
+                write!(f, ": {}", message)?;
+                match expected {
+                    Some(expected) => write!(f, ", expected {}", expected),
+                    None => Ok(()),
+                }
+            }
             DatabaseError::CollectionNotFound(name) => write!(f, "Collection not found: {}", name),
             DatabaseError::PermissionDenied(permission) => write!(f, "Permission not high enough: {}", permission),
             DatabaseError::UserError(error) => write!(f, "Login Error: {}", error), 
             DatabaseError::SerializationError(msg) => write!(f, "Serialization Error: {}", msg),
             DatabaseError::IOError(err) => write!(f, "IO error: {}", err),
             DatabaseError::CollectionError(msg) => write!(f, "Collection Error: {}", msg),
+            DatabaseError::CorruptData { file, offset, reason } => write!(f, "Corrupt data in {} at byte {}: {}", file, offset, reason),
+            DatabaseError::ValidationError { path, reason } => write!(f, "Validation Error: {} {}", path, reason),
+            DatabaseError::EncryptionError(msg) => write!(f, "Encryption Error: {}", msg),
             DatabaseError::Other(msg) => write!(f, "Error: {}", msg),
         }
     }
 }
 
+impl DatabaseError {
+    pub fn syntax(message: impl Into<String>) -> DatabaseError {
+        DatabaseError::SyntaxError { message: message.into(), column: None, expected: None }
+    }
+
+    // Stays the same when the wording of the message changes, so scripts can match on it
+    pub fn code(&self) -> &'static str {
+        match self {
+            DatabaseError::ValueNotFound(_) => "VALUE_NOT_FOUND",
+            DatabaseError::SyntaxError { .. } => "SYNTAX_ERROR",
+            DatabaseError::PermissionDenied(_) => "PERMISSION_DENIED",
+            DatabaseError::CollectionNotFound(_) => "COLLECTION_NOT_FOUND",
+            DatabaseError::UserError(_) => "USER_ERROR",
+            DatabaseError::SerializationError(_) => "SERIALIZATION_ERROR",
+            DatabaseError::IOError(_) => "IO_ERROR",
+            DatabaseError::CollectionError(_) => "COLLECTION_ERROR",
+            DatabaseError::CorruptData { .. } => "CORRUPT_DATA",
+            DatabaseError::ValidationError { .. } => "VALIDATION_ERROR",
+            DatabaseError::EncryptionError(_) => "ENCRYPTION_ERROR",
+            DatabaseError::Other(_) => "OTHER",
+        }
+    }
+
+    // The code and message along with whatever fields the variant has, like where a syntax error is
+    pub fn to_json(&self) -> Value {
+        let mut error = json!({ "code": self.code(), "message": self.to_string() });
+        let details = match self {
+            DatabaseError::SyntaxError { column, expected, .. } => json!({ "column": column, "expected": expected }),
+            DatabaseError::ValidationError { path, reason } => json!({ "path": path, "reason": reason }),
+            DatabaseError::CorruptData { file, offset, reason } => json!({ "file": file, "offset": offset, "reason": reason }),
+            _ => return error,
+        };
+        if let (Some(error), Value::Object(details)) = (error.as_object_mut(), details) {
+            error.extend(details);
+        }
+        error
+    }
+}
+
 impl std::error::Error for DatabaseError {}
 
 impl From<io::Error> for DatabaseError {
@@ -54,6 +116,6 @@ impl From<bcrypt::BcryptError> for DatabaseError {
 
 impl From<serde_json::Error> for DatabaseError {
     fn from( _err: serde_json::Error) -> Self {
-        DatabaseError::Other(format!("incorrect json"))
+        DatabaseError::Other("incorrect json".to_string())
     }
 }
@@ -0,0 +1,308 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use base64::Engine;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request};
+
+use crate::database::{Database, Response};
+use crate::errors::DatabaseError;
+use crate::parser::{Command, Key};
+use crate::session::Session;
+use crate::update::Update;
+
+// The WAL is only written into the collection files once it's grown this big, rewriting every
+// collection after each write would make a write cost as much as the whole database
+const CHECKPOINT_WAL_BYTES: u64 = 1 << 20;
+
+// JSON over HTTP on top of the same commands as the REPL, so they're checked and audited the same way
+//
+//   POST   /sessions                       trade Basic credentials for a bearer token that lasts an hour
+//   DELETE /sessions                       end the bearer token the request was made with
+//   GET    /collections                    the collections you can read, like LIST COLLECTIONS
+//   POST   /collections                    {"name": "..."} creates a collection
+//   DELETE /collections/{c}                drops c along with everything in it
+//   GET    /collections/{c}/keys/{k}       the value stored under k
+//   PUT    /collections/{c}/keys/{k}       stores the request body under k
+//   PATCH  /collections/{c}/keys/{k}       updates k with a merge patch or $ operators, answering with the result
+//   DELETE /collections/{c}/keys/{k}       removes k, answering with what was stored there
+//
+// Every request needs an Authorization header, either Basic with a username and password or
+// Bearer with a token from /sessions or CREATE TOKEN. Errors come back as {"error": "..."}, with 401
+// only when those credentials don't check out.
+pub struct HttpServer {
+    server: tiny_http::Server,
+    database: Arc<Mutex<Database>>,
+}
+
+impl HttpServer {
+    pub fn bind(database: Database, address: &str) -> Result<HttpServer, DatabaseError> {
+        let server = tiny_http::Server::http(address).map_err(|e| DatabaseError::Other(format!("http: {}", e)))?;
+        Ok(HttpServer { server, database: Arc::new(Mutex::new(database)) })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn run(&self) {
+        for request in self.server.incoming_requests() {
+            let database = Arc::clone(&self.database);
+            thread::spawn(move || {
+                HttpServer::handle(request, &database);
+
+                // every write is already in the WAL, this just keeps the WAL from growing forever
+                let mut database = database.lock().unwrap();
+                if database.has_unsaved_changes() && database.wal_size().is_ok_and(|size| size >= CHECKPOINT_WAL_BYTES)
+                    && let Err(e) = database.checkpoint() {
+                    eprintln!("{}", e);
+                }
+            });
+        }
+    }
+
+    fn handle(mut request: Request, database: &Mutex<Database>) {
+        let result = match HttpServer::authorize(&request, database) {
+            Ok(session) => HttpServer::route(&mut request, database, session).map_err(|e| (HttpServer::status_code(&e), e)),
+            // a login error from a command, like dropping a user that isn't there, isn't a 401
+            Err(e @ DatabaseError::UserError(_)) => Err((401, e)),
+            Err(e) => Err((HttpServer::status_code(&e), e)),
+        };
+        let (status, body) = match result {
+            Ok((status, body)) => (status, body),
+            Err((status, e)) => (status, json!({ "error": e.to_string() })),
+        };
+
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = tiny_http::Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            eprintln!("{}", DatabaseError::from(e));
+        }
+    }
+
+    fn route(request: &mut Request, database: &Mutex<Database>, mut session: Session) -> Result<(u16, Value), DatabaseError> {
+        let path: Vec<String> = request.url()
+            .split('?').next().unwrap()
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(HttpServer::percent_decode)
+            .collect::<Result<_, _>>()?;
+        let path: Vec<&str> = path.iter().map(|part| part.as_str()).collect();
+
+        match (request.method(), path.as_slice()) {
+            (Method::Post, ["sessions"]) => {
+                let token = database.lock().unwrap().create_session_token(&session)?;
+                Ok((201, json!({ "token": token })))
+            }
+            (Method::Delete, ["sessions"]) => {
+                let token = HttpServer::authorization(request)
+                    .and_then(|header| header.strip_prefix("Bearer ").map(|token| token.trim().to_string()))
+                    .ok_or(DatabaseError::syntax("DELETE /sessions ends the Bearer token it's sent with"))?;
+                match database.lock().unwrap().revoke_session_token(&token) {
+                    true => Ok((200, json!({ "ok": true }))),
+                    // an API token, which REVOKE TOKEN ends instead
+                    false => Err(DatabaseError::ValueNotFound("no session for that token".to_string())),
+                }
+            }
+            (Method::Get, ["collections"]) => {
+                Ok((200, HttpServer::encode(database.lock().unwrap().execute(&mut session, Command::LISTCOLLECTIONS())?)))
+            }
+            (Method::Delete, ["collections", collection]) => {
+                let response = database.lock().unwrap().execute(&mut session, Command::DROPCOLLECTION(collection.to_string()))?;
+                Ok((200, HttpServer::encode(response)))
+            }
+            (Method::Post, ["collections"]) => {
+                let body = HttpServer::body(request)?;
+                let name = body.get("name").and_then(|name| name.as_str())
+                    .ok_or(DatabaseError::syntax("expected {\"name\": \"...\"}"))?;
+                let response = database.lock().unwrap().execute(&mut session, Command::NEW(name.to_string()))?;
+                Ok((201, HttpServer::encode(response)))
+            }
+            (Method::Get, ["collections", collection, "keys", key]) => {
+                let mut database = database.lock().unwrap();
+                let key = Key::In(collection.to_string(), key.to_string());
+                Ok((200, HttpServer::encode(database.execute(&mut session, Command::GET(key))?)))
+            }
+            (Method::Put, ["collections", collection, "keys", key]) => {
+                let body = HttpServer::body(request)?;
+                let mut database = database.lock().unwrap();
+                let key = Key::In(collection.to_string(), key.to_string());
+                database.execute(&mut session, Command::INSERT(key, body))?;
+                Ok((200, json!({ "ok": true })))
+            }
+            (Method::Patch, ["collections", collection, "keys", key]) => {
+                let body = HttpServer::body(request)?;
+                let update = Update::parse(body)?;
+                let mut database = database.lock().unwrap();
+                let key = Key::In(collection.to_string(), key.to_string());
+                Ok((200, HttpServer::encode(database.execute(&mut session, Command::UPDATE(key, update))?)))
+            }
+            (Method::Delete, ["collections", collection, "keys", key]) => {
+                let mut database = database.lock().unwrap();
+                let key = Key::In(collection.to_string(), key.to_string());
+                Ok((200, HttpServer::encode(database.execute(&mut session, Command::DELETE(key))?)))
+            }
+            _ => Err(DatabaseError::ValueNotFound(format!("{} {}", request.method(), request.url()))),
+        }
+    }
+
+    fn authorization(request: &Request) -> Option<String> {
+        request.headers().iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str().to_string())
+    }
+
+    fn authorize(request: &Request, database: &Mutex<Database>) -> Result<Session, DatabaseError> {
+        let header = HttpServer::authorization(request)
+            .ok_or(DatabaseError::UserError("Authorization header required".to_string()))?;
+
+        match header.split_once(' ') {
+            Some(("Basic", credentials)) => {
+                let decoded = base64::engine::general_purpose::STANDARD.decode(credentials.trim())
+                    .map_err(|_| DatabaseError::UserError("Malformed Basic credentials".to_string()))?;
+                let decoded = String::from_utf8(decoded)
+                    .map_err(|_| DatabaseError::UserError("Malformed Basic credentials".to_string()))?;
+                let (username, password) = decoded.split_once(':')
+                    .ok_or(DatabaseError::UserError("Malformed Basic credentials".to_string()))?;
+                // bcrypt is slow, so the password is checked without holding up everyone else
+                let mut attempt = database.lock().unwrap().begin_login(username.to_string(), password.to_string());
+                attempt.check();
+                database.lock().unwrap().finish_login(attempt)
+            }
+            Some(("Bearer", token)) => database.lock().unwrap().authenticate_token(token.trim()),
+            _ => Err(DatabaseError::UserError("Unsupported authorization scheme".to_string())),
+        }
+    }
+
+    fn body(request: &mut Request) -> Result<Value, DatabaseError> {
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body)?;
+        serde_json::from_str(&body).map_err(|e| DatabaseError::syntax(format!("request body: {}", e)))
+    }
+
+    fn encode(response: Response) -> Value {
+        match response {
+            Response::Value(value) => value,
+            Response::Message(message) => json!({ "message": message }),
+        }
+    }
+
+    pub fn status_code(error: &DatabaseError) -> u16 {
+        match error {
+            DatabaseError::ValueNotFound(_) | DatabaseError::CollectionNotFound(_) => 404,
+            DatabaseError::PermissionDenied(_) => 403,
+            DatabaseError::UserError(_) | DatabaseError::SyntaxError { .. } | DatabaseError::CollectionError(_)
+                | DatabaseError::ValidationError { .. } => 400,
+            DatabaseError::SerializationError(_) | DatabaseError::IOError(_)
+                | DatabaseError::CorruptData { .. } | DatabaseError::EncryptionError(_) | DatabaseError::Other(_) => 500,
+        }
+    }
+
+    fn percent_decode(part: &str) -> Result<String, DatabaseError> {
+        let bytes = part.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let byte = part.get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(DatabaseError::syntax(format!("bad escape in {}", part)))?;
+                decoded.push(byte);
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).map_err(|_| DatabaseError::syntax(format!("{} is not utf-8", part)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tempdir::TempDir;
+
+    use crate::auth::Permissions;
+    use crate::database::Database;
+    use crate::http::{HttpServer, CHECKPOINT_WAL_BYTES};
+
+    // a bare HTTP/1.0 client so the server closes the connection after answering
+    fn request(address: SocketAddr, method: &str, path: &str, auth: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.0\r\nAuthorization: {}\r\nContent-Length: {}\r\n\r\n{}", method, path, auth, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn keys_over_http() {
+        let dir = TempDir::new("http").unwrap();
+        let mut database = Database::init(dir.path().to_str().unwrap().to_string(), &"admin".to_string(), &"password".to_string()).unwrap();
+        let admin = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.create_user(&admin, "user".to_string(), "password".to_string(), Permissions::User()).unwrap();
+        database.create_user(&admin, "guest".to_string(), "password".to_string(), Permissions::Guest()).unwrap();
+
+        let server = HttpServer::bind(database, "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // user:password and guest:password
+        let user = "Basic dXNlcjpwYXNzd29yZA==";
+        let guest = "Basic Z3Vlc3Q6cGFzc3dvcmQ=";
+
+        assert_eq!(request(address, "POST", "/collections", "Basic bm86bm8=", "{\"name\":\"c\"}").0, 401);
+        assert_eq!(request(address, "POST", "/collections", user, "{\"name\":\"c\"}").0, 201);
+        assert_eq!(request(address, "PUT", "/collections/c/keys/a%20b", user, "{\"x\":1}").0, 200);
+        assert_eq!(request(address, "PUT", "/collections/c/keys/a", guest, "1").0, 403);
+        assert_eq!(request(address, "GET", "/collections/c/keys/a%20b", guest, ""), (200, json!({"x": 1})));
+        assert_eq!(request(address, "PATCH", "/collections/c/keys/a%20b", user, "{\"$inc\":{\"x\":1}}"), (200, json!({"x": 2})));
+        assert_eq!(request(address, "PATCH", "/collections/c/keys/a%20b", user, "{\"x\":null,\"y\":1}"), (200, json!({"y": 1})));
+        assert_eq!(request(address, "PUT", "/collections/c/keys/a%20b", user, "{\"x\":1}").0, 200);
+        assert_eq!(request(address, "GET", "/collections/missing/keys/a", user, "").0, 404);
+
+        // small writes stay in the WAL, it's only checkpointed once it's grown past the threshold
+        let wal = dir.path().join("wal.log");
+        thread::sleep(Duration::from_millis(100));
+        assert!(fs::metadata(&wal).unwrap().len() > 0);
+        let big = json!({"x": "a".repeat(CHECKPOINT_WAL_BYTES as usize)}).to_string();
+        assert_eq!(request(address, "PUT", "/collections/c/keys/big", user, &big).0, 200);
+        assert!((0..200).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            fs::metadata(&wal).is_ok_and(|metadata| metadata.len() == 0)
+        }));
+        assert_eq!(request(address, "DELETE", "/collections/c/keys/big", user, "").0, 200);
+
+        let (status, body) = request(address, "POST", "/sessions", user, "");
+        assert_eq!(status, 201);
+        let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+        assert_eq!(request(address, "DELETE", "/collections/c/keys/a%20b", &bearer, ""), (200, json!({"x": 1})));
+        assert_eq!(request(address, "GET", "/collections/c/keys/a%20b", &bearer, "").0, 404);
+        // a session can be ended early, only with its own token
+        assert_eq!(request(address, "DELETE", "/sessions", user, "").0, 400);
+        assert_eq!(request(address, "DELETE", "/sessions", &bearer, ""), (200, json!({"ok": true})));
+        assert_eq!(request(address, "GET", "/collections", &bearer, "").0, 401);
+
+        let (status, listed) = request(address, "GET", "/collections", guest, "");
+        assert_eq!((status, &listed[0]["name"], &listed[0]["keys"]), (200, &json!("c"), &json!(0)));
+        assert_eq!(request(address, "POST", "/collections", user, "{\"name\":\"../c\"}").0, 400);
+        assert_eq!(request(address, "DELETE", "/collections/c", user, "").0, 403);
+        assert_eq!(request(address, "DELETE", "/collections/c", "Basic YWRtaW46cGFzc3dvcmQ=", "").0, 200);
+        assert_eq!(request(address, "GET", "/collections/c/keys/a", user, "").0, 404);
+    }
+}
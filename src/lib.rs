@@ -0,0 +1,48 @@
+//! An embeddable key/value store of JSON documents grouped into collections, backed by a write
+//! ahead log and per collection snapshots.
+//!
+//! ```no_run
+//! use database::{Database, OpenOptions, Permissions};
+//!
+//! # fn main() -> Result<(), database::DatabaseError> {
+//! let mut db = Database::open("./data".to_string(), OpenOptions::default())?;
+//! db.login("service".to_string(), "password".to_string())?;
+//!
+//! let mut users = db.collection("users")?;
+//! users.insert("alice", &serde_json::json!({ "age": 30 }))?;
+//! let alice: Option<serde_json::Value> = users.get("alice")?;
+//!
+//! // command strings work too
+//! let command = database::Parser::new().get_command("SELECT users")?;
+//! db.operate_db(command)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![allow(clippy::upper_case_acronyms)]
+
+mod collections;
+mod wal;
+mod storage;
+mod schema;
+pub mod crypto;
+pub mod audit;
+pub mod parser;
+pub mod query;
+pub mod update;
+pub mod database;
+pub mod auth;
+pub mod session;
+pub mod errors;
+pub mod server;
+pub mod output;
+pub mod http;
+
+pub use crate::database::{Database, CollectionHandle, OpenOptions, RecoveryPolicy, Response};
+pub use crate::parser::{Parser, Command, Key};
+pub use crate::query::Query;
+pub use crate::auth::Permissions;
+pub use crate::crypto::KeySource;
+pub use crate::session::Session;
+pub use crate::errors::DatabaseError;
+pub use crate::output::{Output, Printer};
@@ -1,36 +1,145 @@
-mod collections;
-mod parser;
-mod database;
-mod wal;
-mod auth;
-mod session;
-mod errors;
+#![allow(clippy::upper_case_acronyms)]
+
 mod cli;
 
-use crate::parser::Parser;
-use crate::database::Database;
-use crate::auth::Permissions;
-use crate::cli::CLI;
+use std::fs::File;
+use std::io::{self, BufReader, IsTerminal};
+use std::process::ExitCode;
+
+use database::{Database, DatabaseError, OpenOptions, Output, Parser};
+use database::server::Server;
+use database::http::HttpServer;
+use crate::cli::{CLI, Commands};
+
+fn main() -> ExitCode {
+    let args = CLI::get_args();
 
-fn main() {
-    let (username, password, dir, new_user) = CLI::get_args();
+    // scripts, --execute and piped input run without prompts and print JSON, the REPL is for people
+    let batch = !args.execute.is_empty() || args.file.is_some() || !io::stdin().is_terminal();
+    let output = args.output.unwrap_or(if batch { Output::Ndjson } else { Output::Pretty });
+    // every error, from opening the database on, goes to stderr with its code
+    let fail = |e: DatabaseError| {
+        eprintln!("{}", output.render_error(&e));
+        ExitCode::FAILURE
+    };
+
+    if let Some(Commands::Init { admin, encrypt }) = args.command {
+        // everything is asked for before anything is written
+        let result = CLI::new_admin(admin).and_then(|admin| {
+            let key = match encrypt {
+                true => Some(CLI::new_key(args.key_file.clone(), args.passphrase_file.clone(), "DATABASE_PASSPHRASE")?),
+                false => None,
+            };
+            Ok((admin, key))
+        }).map_err(DatabaseError::Other).and_then(|((username, password), key)| {
+            let mut database = Database::init(args.dir.clone(), &username, &password)?;
+            match key {
+                Some(key) => database.rekey(Some(&key)),
+                None => Ok(()),
+            }
+        });
+        return match result {
+            Ok(_) => {
+                println!("Created a database in {}", args.dir);
+                ExitCode::SUCCESS
+            }
+            Err(e) => fail(e),
+        };
+    }
 
     // loads database if that directory already has a valid database
-    let mut database = Database::new(dir);
+    let key = match args.key(&args.dir) {
+        Ok(key) => key,
+        Err(e) => return fail(DatabaseError::Other(e)),
+    };
+    let options = OpenOptions { recovery: args.on_corruption, key };
+    let mut database = match Database::open(args.dir.clone(), options) {
+        Ok(database) => database,
+        Err(e) => return fail(e),
+    };
 
-    if new_user {
-        database.new_user(&username, &password, Permissions::User()).unwrap();
+    if let Some(Commands::Rekey { new_key_file, new_passphrase_file, decrypt }) = args.command {
+        let result = match decrypt {
+            true => database.rekey(None),
+            false => CLI::new_key(new_key_file, new_passphrase_file, "DATABASE_NEW_PASSPHRASE")
+                .map_err(DatabaseError::Other)
+                .and_then(|key| database.rekey(Some(&key))),
+        };
+        match result {
+            Ok(_) if decrypt => println!("Decrypted {}", args.dir),
+            Ok(_) => println!("Encrypted {} with the new key", args.dir),
+            Err(e) => return fail(e),
+        }
+        return ExitCode::SUCCESS;
     }
 
-    match database.login(username.to_string(), password.to_string()) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("{}", e);
-            return ();
+    if let Some(Commands::Serve { address }) = args.command {
+        let server = match Server::bind(database, &address) {
+            Ok(server) => server,
+            Err(e) => return fail(e),
+        };
+        match server.local_addr() {
+            Ok(address) => println!("Listening on {}", address),
+            Err(e) => return fail(e),
+        }
+        if let Err(e) = server.run() {
+            return fail(e);
         }
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(Commands::Http { address }) = args.command {
+        let server = match HttpServer::bind(database, &address) {
+            Ok(server) => server,
+            Err(e) => return fail(e),
+        };
+        if let Some(address) = server.local_addr() {
+            println!("Listening on http://{}", address);
+        }
+        server.run();
+        return ExitCode::SUCCESS;
+    }
+
+    // clap makes sure there is a username when no subcommand was given
+    let username = args.username.clone().unwrap();
+    let password = match args.password(&username) {
+        Ok(password) => password,
+        Err(e) => return fail(DatabaseError::Other(e)),
+    };
+
+    // only an admin can add users, so --new-user logs in as the one given by --admin first
+    if args.new_user {
+        let admin = args.admin.unwrap();
+        let result = std::env::var("DATABASE_ADMIN_PASSWORD")
+            .or_else(|_| CLI::prompt_password(&format!("Password for {}", admin)))
+            .map_err(DatabaseError::from)
+            .and_then(|admin_password| database.authenticate(admin, admin_password))
+            .and_then(|session| database.create_user(&session, username.clone(), password.clone(), args.role));
+        if let Err(e) = result {
+            return fail(e);
+        }
+    }
+
+    if let Err(e) = database.login(username.to_string(), password.to_string()) {
+        return fail(e);
     }
     let parser = Parser::new();
 
-    CLI::start_repl(database, parser);
+    let status = if !args.execute.is_empty() {
+        let mut commands = args.execute.into_iter();
+        CLI::batch(&mut database, &parser, || Ok(commands.next()), args.stop_on_error, output)
+    } else if let Some(file) = &args.file {
+        let mut reader = match File::open(file) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => return fail(DatabaseError::IOError(io::Error::new(e.kind(), format!("{}: {}", file, e)))),
+        };
+        CLI::batch(&mut database, &parser, || CLI::read_command(&mut reader, &parser, None), args.stop_on_error, output)
+    } else if batch {
+        let mut reader = io::stdin().lock();
+        CLI::batch(&mut database, &parser, || CLI::read_command(&mut reader, &parser, None), args.stop_on_error, output)
+    } else {
+        CLI::start_repl(database, parser, output);
+        return ExitCode::SUCCESS;
+    };
+    ExitCode::from(status)
 }
-
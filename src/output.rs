@@ -0,0 +1,228 @@
+use std::io::{self, Write};
+
+use serde_json::{json, Value};
+
+use crate::database::Response;
+use crate::errors::DatabaseError;
+
+// How the command line prints results. Whatever worked goes to stdout and errors go to stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    // every result of the run in one indented JSON array, see Printer. Errors go to stderr an
+    // object to a line like ndjson's
+    Json,
+    // each result as a JSON object on one line
+    Ndjson,
+    // lists of documents, FIND and pages from SCAN and RANGE as aligned columns, anything else as
+    // it would be with pretty
+    Table,
+    // messages as they are and values as indented JSON
+    Pretty,
+}
+
+impl std::str::FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Output::Json),
+            "ndjson" => Ok(Output::Ndjson),
+            "table" => Ok(Output::Table),
+            "pretty" => Ok(Output::Pretty),
+            _ => Err(format!("unknown output {}, expected json, ndjson, table or pretty", s)),
+        }
+    }
+}
+
+impl Output {
+    // What to print for a command that worked, None when there's nothing worth printing
+    pub fn render(&self, response: &Response) -> Option<String> {
+        let encoded = match response {
+            Response::Value(value) => json!({ "ok": true, "value": value }),
+            Response::Message(message) => json!({ "ok": true, "message": message }),
+        };
+        match (self, response) {
+            (Output::Json, _) => serde_json::to_string_pretty(&encoded).ok(),
+            (Output::Ndjson, _) => Some(encoded.to_string()),
+            (_, Response::Message(message)) => Some(message.clone()),
+            (_, Response::Value(Value::Null)) => None,
+            (Output::Table, Response::Value(value)) => table(value).or_else(|| serde_json::to_string_pretty(value).ok()),
+            (Output::Pretty, Response::Value(value)) => serde_json::to_string_pretty(value).ok(),
+        }
+    }
+
+    // What to print on stderr for a command that didn't work
+    pub fn render_error(&self, error: &DatabaseError) -> String {
+        let encoded = json!({ "ok": false, "error": error.to_json() });
+        match self {
+            Output::Json | Output::Ndjson => encoded.to_string(),
+            Output::Table | Output::Pretty => format!("{} [{}]", error, error.code()),
+        }
+    }
+}
+
+// Writes the results of a run as they come. json's are elements of an array that's opened before
+// the first and closed by finish, so a run that printed nothing is still [] and the whole of
+// stdout parses as one document
+pub struct Printer<W: Write> {
+    output: Output,
+    out: W,
+    printed: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(output: Output, out: W) -> Printer<W> {
+        Printer { output, out, printed: false }
+    }
+
+    pub fn print(&mut self, response: &Response) -> io::Result<()> {
+        let Some(rendered) = self.output.render(response) else { return Ok(()) };
+        if self.output == Output::Json {
+            let separator = if self.printed { "," } else { "[" };
+            writeln!(self.out, "{}", separator)?;
+            write!(self.out, "  {}", rendered.replace('\n', "\n  "))?;
+        } else {
+            writeln!(self.out, "{}", rendered)?;
+        }
+        self.printed = true;
+        self.out.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.output == Output::Json {
+            match self.printed {
+                true => writeln!(self.out, "\n]")?,
+                false => writeln!(self.out, "[]")?,
+            }
+        }
+        self.out.flush()
+    }
+}
+
+// A list of objects or a page of them as a table with a column per field, None for anything else.
+// {"key": ..., "value": ...} rows from FIND, SCAN and RANGE get a column for each field of the
+// document instead of one for the whole value
+pub fn table(value: &Value) -> Option<String> {
+    let (items, cursor) = match value {
+        Value::Array(items) => (items, None),
+        Value::Object(page) => match (page.get("items"), page.get("cursor")) {
+            (Some(Value::Array(items)), Some(cursor)) if page.len() == 2 => (items, Some(cursor)),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let rows: Vec<Vec<(String, &Value)>> = items.iter().map(row).collect::<Option<_>>()?;
+
+    let mut columns: Vec<&str> = Vec::new();
+    for (name, _) in rows.iter().flatten() {
+        if !columns.contains(&name.as_str()) {
+            columns.push(name);
+        }
+    }
+    let cells: Vec<Vec<String>> = rows.iter().map(|row| columns.iter().map(|column| {
+        match row.iter().find(|(name, _)| name == column).map(|(_, value)| *value) {
+            Some(Value::String(string)) => string.replace('\n', "\\n"),
+            Some(value) => value.to_string(),
+            None => String::new(),
+        }
+    }).collect()).collect();
+    let widths: Vec<usize> = columns.iter().enumerate()
+        .map(|(i, column)| cells.iter().map(|row| row[i].chars().count()).chain([column.chars().count()]).max().unwrap_or_default())
+        .collect();
+    // numbers line up on the right like they would in a spreadsheet
+    let numeric: Vec<bool> = (0..columns.len())
+        .map(|i| rows.iter().all(|row| row.iter().find(|(name, _)| name == columns[i]).is_none_or(|(_, value)| value.is_number())))
+        .collect();
+
+    let line = |values: Vec<&str>| -> String {
+        let padded: Vec<String> = values.iter().enumerate().map(|(i, value)| match numeric[i] {
+            true => format!("{:>width$}", value, width = widths[i]),
+            false => format!("{:<width$}", value, width = widths[i]),
+        }).collect();
+        padded.join(" | ").trim_end().to_string()
+    };
+    let mut lines = Vec::new();
+    if !columns.is_empty() {
+        lines.push(line(columns.clone()));
+        lines.push(widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<_>>().join("-+-"));
+        lines.extend(cells.iter().map(|row| line(row.iter().map(String::as_str).collect())));
+    }
+    lines.push(format!("({} {})", rows.len(), if rows.len() == 1 { "row" } else { "rows" }));
+    if let Some(Value::String(cursor)) = cursor {
+        lines.push(format!("more after {}", cursor));
+    }
+    Some(lines.join("\n"))
+}
+
+fn row(item: &Value) -> Option<Vec<(String, &Value)>> {
+    let object = item.as_object()?;
+    let (Some(key), Some(value), 2) = (object.get("key"), object.get("value"), object.len()) else {
+        return Some(object.iter().map(|(name, value)| (name.clone(), value)).collect())
+    };
+    let mut row = vec![("key".to_string(), key)];
+    match value {
+        // a field that's also called key is told apart from the document's key
+        Value::Object(fields) => row.extend(fields.iter().map(|(name, value)| match name.as_str() {
+            "key" => ("value.key".to_string(), value),
+            _ => (name.clone(), value),
+        })),
+        value => row.push(("value".to_string(), value)),
+    }
+    Some(row)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::database::Response;
+    use crate::errors::DatabaseError;
+    use crate::output::{table, Output, Printer};
+
+    #[test]
+    fn tables_and_structured_errors() {
+        let found = json!([
+            {"key": "user:1", "value": {"name": "Alice", "age": 30}},
+            {"key": "user:10", "value": {"name": "Bob", "age": 7, "tags": ["a"]}},
+        ]);
+        assert_eq!(table(&found).unwrap(), [
+            "key     | age | name  | tags",
+            "--------+-----+-------+------",
+            "user:1  |  30 | Alice |",
+            "user:10 |   7 | Bob   | [\"a\"]",
+            "(2 rows)",
+        ].join("\n"));
+
+        let page = json!({"items": [{"key": "a", "value": 1}], "cursor": "a"});
+        assert_eq!(table(&page).unwrap(), "key | value\n----+------\na   |     1\n(1 row)\nmore after a");
+        assert_eq!(table(&json!([])).unwrap(), "(0 rows)");
+        assert_eq!(table(&json!([1, 2])), None);
+        assert_eq!(table(&json!({"name": "a"})), None);
+        assert_eq!(Output::Table.render(&Response::Value(json!({"b": 1}))).unwrap(), "{\n  \"b\": 1\n}");
+        assert_eq!(Output::Ndjson.render(&Response::Message("t selected".to_string())).unwrap(), r#"{"message":"t selected","ok":true}"#);
+        assert_eq!(Output::Pretty.render(&Response::Value(json!(null))), None);
+
+        // json output from a whole run reads back as one array, even when nothing was printed
+        let run = |responses: &[Response]| -> serde_json::Value {
+            let mut out = Vec::new();
+            let mut printer = Printer::new(Output::Json, &mut out);
+            responses.iter().for_each(|response| printer.print(response).unwrap());
+            printer.finish().unwrap();
+            serde_json::from_slice(&out).unwrap()
+        };
+        assert_eq!(run(&[Response::Message("t selected".to_string()), Response::Value(json!(null)), Response::Value(json!({"b": [1, 2]}))]),
+            json!([{"ok": true, "message": "t selected"}, {"ok": true, "value": null}, {"ok": true, "value": {"b": [1, 2]}}]));
+        assert_eq!(run(&[]), json!([]));
+
+        let error = DatabaseError::SyntaxError { message: "found }".to_string(), column: Some(11), expected: Some("a JSON value".to_string()) };
+        let encoded: serde_json::Value = serde_json::from_str(&Output::Ndjson.render_error(&error)).unwrap();
+        assert_eq!(encoded, json!({"ok": false, "error": {
+            "code": "SYNTAX_ERROR",
+            "message": "Syntax Error at column 11: found }, expected a JSON value",
+            "column": 11,
+            "expected": "a JSON value",
+        }}));
+        assert_eq!(Output::Pretty.render_error(&DatabaseError::ValueNotFound("a".to_string())), "Value not found: a [VALUE_NOT_FOUND]");
+    }
+}
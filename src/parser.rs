@@ -1,136 +1,708 @@
+use std::fmt;
+
 use serde_json::Value;
-use serde_json::json;
 
+use crate::audit::AuditFilter;
+use crate::auth::{Access, Grantee, Permissions};
 use crate::errors::DatabaseError;
+use crate::query::Query;
+use crate::update::Update;
 
 
+#[derive(Default)]
 pub struct Parser {
 }
 
+// A key as a command names it. Written bare as collection.key it's in that collection when the part
+// before the first dot names one, and a quoted key is always in the selected collection, so a key
+// with a dot in it stays reachable when a collection of that name turns up later
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Bare(String),
+    Quoted(String),
+    // collection and the key in it, whatever the key looks like
+    In(String, String),
+}
+
+impl From<String> for Key {
+    fn from(key: String) -> Key {
+        Key::Bare(key)
+    }
+}
+
+impl From<&str> for Key {
+    fn from(key: &str) -> Key {
+        Key::Bare(key.to_string())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Bare(key) | Key::Quoted(key) => write!(f, "{}", key),
+            Key::In(collection, key) => write!(f, "{}.{}", collection, key),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
-    INSERT(String, Value),
-    GET(String),
-    DELETE(String),
+    INSERT(Key, Value),
+    // key and what to change in the document under it
+    UPDATE(Key, Update),
+    GET(Key),
+    DELETE(Key),
     SELECT(String),
     NEW(String),
     WHICH(String),
-    ERROR(),
+    BEGIN(),
+    COMMIT(),
+    ROLLBACK(),
+    // collection and the json path being indexed
+    CREATEINDEX(String, String),
+    DROPINDEX(String, String),
+    FIND(Query),
+    // key prefix or the from and to keys, then the page size and the cursor to carry on after
+    SCAN(Key, Option<usize>, Option<String>),
+    RANGE(Key, Key, Option<usize>, Option<String>),
+    // access level, collection and who it's granted to or revoked from
+    GRANT(Access, String, Grantee),
+    REVOKE(Access, String, Grantee),
+    // username, password and role
+    CREATEUSER(String, String, Permissions),
+    DROPUSER(String),
+    ALTERPASSWORD(String, String),
+    ALTERROLE(String, Permissions),
+    LISTUSERS(),
+    UNLOCKUSER(String),
+    AUDIT(AuditFilter),
+    // who it's for (whoever asks when left out), how many seconds it lasts and whether it's read-only
+    CREATETOKEN(Option<String>, Option<u64>, bool),
+    LISTTOKENS(),
+    REVOKETOKEN(String),
+    // collection and the schema to set, null takes it off and None shows the current one
+    SCHEMA(String, Option<Value>),
+    LISTCOLLECTIONS(),
+    DROPCOLLECTION(String),
+    // collection and its new name
+    RENAMECOLLECTION(String, String),
 }
 
-pub enum Token {
-    INSERT,
-    GET,
-    DELETE,
-    SELECT,
-    WHICH,
-    NEW, 
-    IDENTIFIER(String),
-    JSON(Value),
+impl Command {
+    // The name of the operation, the collection it names if it names one and what it acts on, as
+    // written to the audit log. Passwords and values are left out
+    pub fn describe(&self) -> (&'static str, Option<String>, Option<String>) {
+        match self {
+            Command::INSERT(key, _) => ("INSERT", None, Some(key.to_string())),
+            Command::UPDATE(key, _) => ("UPDATE", None, Some(key.to_string())),
+            Command::GET(key) => ("GET", None, Some(key.to_string())),
+            Command::DELETE(key) => ("DELETE", None, Some(key.to_string())),
+            Command::SELECT(name) => ("SELECT", Some(name.clone()), None),
+            Command::NEW(name) => ("NEW", Some(name.clone()), None),
+            Command::WHICH(what) => ("WHICH", None, Some(what.clone())),
+            Command::BEGIN() => ("BEGIN", None, None),
+            Command::COMMIT() => ("COMMIT", None, None),
+            Command::ROLLBACK() => ("ROLLBACK", None, None),
+            Command::CREATEINDEX(collection, path) => ("CREATEINDEX", Some(collection.clone()), Some(path.clone())),
+            Command::DROPINDEX(collection, path) => ("DROPINDEX", Some(collection.clone()), Some(path.clone())),
+            Command::FIND(query) => ("FIND", query.collection.clone(), None),
+            Command::SCAN(prefix, _, _) => ("SCAN", None, Some(prefix.to_string())),
+            Command::RANGE(from, to, _, _) => ("RANGE", None, Some(format!("{}..{}", from, to))),
+            Command::GRANT(_, collection, grantee) => ("GRANT", Some(collection.clone()), Some(grantee.to_string())),
+            Command::REVOKE(_, collection, grantee) => ("REVOKE", Some(collection.clone()), Some(grantee.to_string())),
+            Command::CREATEUSER(username, _, _) => ("CREATEUSER", None, Some(username.clone())),
+            Command::DROPUSER(username) => ("DROPUSER", None, Some(username.clone())),
+            Command::ALTERPASSWORD(username, _) => ("ALTERPASSWORD", None, Some(username.clone())),
+            Command::ALTERROLE(username, _) => ("ALTERROLE", None, Some(username.clone())),
+            Command::LISTUSERS() => ("LISTUSERS", None, None),
+            Command::UNLOCKUSER(username) => ("UNLOCKUSER", None, Some(username.clone())),
+            Command::AUDIT(_) => ("AUDIT", None, None),
+            Command::CREATETOKEN(owner, _, _) => ("CREATETOKEN", None, owner.clone()),
+            Command::LISTTOKENS() => ("LISTTOKENS", None, None),
+            Command::REVOKETOKEN(id) => ("REVOKETOKEN", None, Some(id.clone())),
+            Command::SCHEMA(collection, _) => ("SCHEMA", Some(collection.clone()), None),
+            Command::LISTCOLLECTIONS() => ("LISTCOLLECTIONS", None, None),
+            Command::DROPCOLLECTION(name) => ("DROPCOLLECTION", Some(name.clone()), None),
+            Command::RENAMECOLLECTION(name, to) => ("RENAMECOLLECTION", Some(name.clone()), Some(to.clone())),
+        }
+    }
+}
+
+// Command words the grammar starts from
+const COMMANDS: [&str; 23] = [
+    "INSERT", "UPDATE", "GET", "DELETE", "SELECT", "NEW", "WHICH", "BEGIN", "COMMIT", "ROLLBACK", "CREATE", "DROP",
+    "ALTER", "LIST", "UNLOCK", "RENAME", "FIND", "SCAN", "RANGE", "GRANT", "REVOKE", "AUDIT", "SCHEMA",
+];
 
+// Where parsing has got to in a command. Keys and names are a bare word or a double quoted string
+// with the same escapes as JSON, so "order 1" and "tab\tseparated" work, and values are JSON that
+// can run over as many lines as it needs
+struct Cursor<'a> {
+    input: &'a str,
+    // byte offset of whatever comes next
+    position: usize,
+    // the input ran out inside a quoted string or a JSON value, so another line might finish it
+    unfinished: bool,
 }
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Cursor<'a> {
+        Cursor { input, position: 0, unfinished: false }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.position == self.input.len()
+    }
+
+    // counted in characters from 1
+    fn column(&self, at: usize) -> usize {
+        self.input[..at].chars().count() + 1
+    }
+
+    // What was found at the offset and what should have been there instead
+    fn error(&self, at: usize, expected: &str) -> DatabaseError {
+        let message = match self.input[at..].split_whitespace().next() {
+            Some(word) if word.chars().count() > 20 => format!("found {}...", word.chars().take(20).collect::<String>()),
+            Some(word) => format!("found {}", word),
+            None => "found the end of the command".to_string(),
+        };
+        DatabaseError::SyntaxError { message, column: Some(self.column(at)), expected: Some(expected.to_string()) }
+    }
+
+    // Puts the column on an error from checking something that parsed, like a role that doesn't exist
+    fn locate(&self, at: usize, error: DatabaseError) -> DatabaseError {
+        match error {
+            DatabaseError::SyntaxError { message, column: None, expected } => DatabaseError::SyntaxError { message, column: Some(self.column(at)), expected },
+            error => error,
+        }
+    }
+
+    // The next bare word without taking it. A quoted string is never a keyword
+    fn peek_word(&mut self) -> Option<&'a str> {
+        self.skip_whitespace();
+        match self.rest().starts_with('"') {
+            true => None,
+            false => self.rest().split_whitespace().next(),
+        }
+    }
+
+    // Takes the next word if it's one of the keywords and hands it back in upper case
+    fn keyword(&mut self, keywords: &[&str]) -> Option<String> {
+        let word = self.peek_word()?;
+        let keyword = keywords.iter().find(|keyword| word.eq_ignore_ascii_case(keyword))?;
+        self.position += word.len();
+        Some(keyword.to_string())
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), DatabaseError> {
+        match self.keyword(&[keyword]) {
+            Some(_) => Ok(()),
+            None => Err(self.error(self.position, keyword)),
+        }
+    }
+
+    // A bare word or a quoted string
+    fn name(&mut self, expected: &str) -> Result<String, DatabaseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        if self.rest().starts_with('"') {
+            return match self.json(expected)? {
+                Value::String(name) => Ok(name),
+                _ => Err(self.error(start, expected)),
+            }
+        }
+        match self.rest().split_whitespace().next() {
+            Some(word) => {
+                self.position += word.len();
+                Ok(word.to_string())
+            }
+            None => Err(self.error(start, expected)),
+        }
+    }
+
+    // A key, remembering whether it was quoted
+    fn key(&mut self) -> Result<Key, DatabaseError> {
+        self.skip_whitespace();
+        let quoted = self.rest().starts_with('"');
+        let key = self.name("a key")?;
+        Ok(if quoted { Key::Quoted(key) } else { Key::Bare(key) })
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, expected: &str) -> Result<T, DatabaseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        self.name(expected)?.parse().map_err(|_| self.error(start, expected))
+    }
+
+    // A single JSON value. Anything that isn't one is an error, it never turns into null
+    fn json(&mut self, expected: &str) -> Result<Value, DatabaseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        let mut values = serde_json::Deserializer::from_str(self.rest()).into_iter::<Value>();
+        let e = match values.next() {
+            Some(Ok(value)) => {
+                self.position += values.byte_offset();
+                return Ok(value)
+            }
+            None => return Err(self.error(start, expected)),
+            Some(Err(e)) => e,
+        };
+        self.unfinished = e.is_eof();
+
+        if e.is_eof() {
+            return Err(DatabaseError::SyntaxError {
+                message: "found the end of the command".to_string(),
+                column: Some(self.column(self.input.len())),
+                expected: Some(format!("{} to be finished", expected)),
+            })
+        }
+        // serde_json counts lines from 1 and columns in bytes from 1
+        let line_start: usize = self.rest().split_inclusive('\n').take(e.line().saturating_sub(1)).map(str::len).sum();
+        let mut at = (start + line_start + e.column().saturating_sub(1)).min(self.input.len());
+        while !self.input.is_char_boundary(at) {
+            at -= 1;
+        }
+        let reason = e.to_string();
+        let reason = reason.split(" at line ").next().unwrap_or_default();
+        match reason.starts_with("expected") {
+            // serde_json's "expected value" and the like say less than what's actually there
+            true => Err(self.error(at, expected)),
+            false => Err(DatabaseError::SyntaxError { message: reason.to_string(), column: Some(self.column(at)), expected: Some(expected.to_string()) }),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), DatabaseError> {
+        match self.at_end() {
+            true => Ok(()),
+            false => Err(self.error(self.position, "the end of the command")),
+        }
+    }
+}
+
 impl Parser {
     pub fn new()-> Parser  {
         Parser {}
     }
 
-    pub fn get_command(&self, line: &str) -> Result<Command, DatabaseError> {
-        let tokens = Parser::lexer(line)?;
+    pub fn get_command(&self, input: &str) -> Result<Command, DatabaseError> {
+        Parser::command(&mut Cursor::new(input))
+    }
 
-        Parser::parse(tokens)
+    // Whether the input stops partway through a quoted string or a JSON value, for front ends
+    // that read a line at a time to know there's more of the command to come
+    pub fn is_unfinished(&self, input: &str) -> bool {
+        let mut cursor = Cursor::new(input);
+        Parser::command(&mut cursor).is_err() && cursor.unfinished
     }
 
-    fn parse(tokens: Vec<Token>) -> Result<Command, DatabaseError> { 
-        match tokens.get(0) {
-            Some(Token::INSERT) => {
-                if let (Some(Token::IDENTIFIER(key)), Some(Token::JSON(value))) = (tokens.get(1), tokens.get(2)) {
-                    Ok(Command::INSERT(key.clone(), value.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("Missing identifier or json".to_string()))
-                }
+    fn command(cursor: &mut Cursor) -> Result<Command, DatabaseError> {
+        let word = cursor.keyword(&COMMANDS).ok_or_else(|| cursor.error(cursor.position, "a command"))?;
+        let command = match word.as_str() {
+            "INSERT" => Command::INSERT(cursor.key()?, cursor.json("a JSON value")?),
+            "UPDATE" => {
+                let key = cursor.key()?;
+                cursor.skip_whitespace();
+                let at = cursor.position;
+                let patch = cursor.json("a merge patch or $ operators")?;
+                Command::UPDATE(key, Update::parse(patch).map_err(|e| cursor.locate(at, e))?)
             }
-            Some(Token::GET) => {
-                if let Some(Token::IDENTIFIER(key)) = tokens.get(1) {
-                    Ok(Command::GET(key.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("Missing identifier".to_string()))
-                }
+            "GET" => Command::GET(cursor.key()?),
+            "DELETE" => Command::DELETE(cursor.key()?),
+            "SELECT" => Command::SELECT(cursor.name("a collection")?),
+            "NEW" => Command::NEW(cursor.name("a collection")?),
+            "WHICH" => Command::WHICH(cursor.name("collection, path or user")?),
+            "BEGIN" => Command::BEGIN(),
+            "COMMIT" => Command::COMMIT(),
+            "ROLLBACK" => Command::ROLLBACK(),
+            "FIND" => {
+                // the query has its own grammar, its columns are moved along past FIND
+                let offset = cursor.column(cursor.position) - 1;
+                let query = Query::parse(cursor.rest()).map_err(|e| match e {
+                    DatabaseError::SyntaxError { message, column: Some(column), expected } => {
+                        // a quote or bracket left open might be closed on the next line
+                        cursor.unfinished = expected.as_deref().is_some_and(|expected| expected.starts_with("a closing"));
+                        DatabaseError::SyntaxError { message, column: Some(column + offset), expected }
+                    }
+                    e => e,
+                })?;
+                cursor.position = cursor.input.len();
+                Command::FIND(query)
             }
-            Some(Token::DELETE) => {
-                if let Some(Token::IDENTIFIER(key)) = tokens.get(1) {
-                    Ok(Command::DELETE(key.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("".to_string()))
+            "SCAN" | "RANGE" => Parser::scan(cursor, &word)?,
+            "GRANT" => Parser::grant(cursor, &word)?,
+            "REVOKE" => match cursor.keyword(&["TOKEN"]) {
+                Some(_) => Command::REVOKETOKEN(cursor.name("a token id")?),
+                None => Parser::grant(cursor, &word)?,
+            },
+            "AUDIT" => Parser::audit(cursor)?,
+            "SCHEMA" => {
+                let collection = cursor.name("a collection")?;
+                match cursor.at_end() {
+                    true => Command::SCHEMA(collection, None),
+                    false => Command::SCHEMA(collection, Some(cursor.json("a JSON schema or null")?)),
                 }
             }
-            Some(Token::SELECT) => {
-                if let Some(Token::IDENTIFIER(name)) = tokens.get(1) {
-                    Ok(Command::SELECT(name.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("Missing Identifier".to_string()))
+            "CREATE" => match cursor.keyword(&["INDEX", "USER", "TOKEN"]).as_deref() {
+                Some("INDEX") => Parser::index(cursor, true)?,
+                Some("USER") => {
+                    let username = cursor.name("a username")?;
+                    cursor.expect_keyword("PASSWORD")?;
+                    let password = cursor.name("a password")?;
+                    let role = match cursor.keyword(&["ROLE"]) {
+                        Some(_) => Parser::role(cursor)?,
+                        None => Permissions::User(),
+                    };
+                    Command::CREATEUSER(username, password, role)
                 }
-            }
-            Some(Token::NEW) => {
-                if let Some(Token::IDENTIFIER(name)) = tokens.get(1) {
-                    Ok(Command::NEW(name.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("Missing Identifier".to_string()))
+                Some(_) => Parser::create_token(cursor)?,
+                None => return Err(cursor.error(cursor.position, "INDEX, USER or TOKEN")),
+            },
+            "DROP" => match cursor.keyword(&["INDEX", "USER", "COLLECTION"]).as_deref() {
+                Some("INDEX") => Parser::index(cursor, false)?,
+                Some("USER") => Command::DROPUSER(cursor.name("a username")?),
+                Some(_) => Command::DROPCOLLECTION(cursor.name("a collection")?),
+                None => return Err(cursor.error(cursor.position, "INDEX, USER or COLLECTION")),
+            },
+            "ALTER" => {
+                cursor.expect_keyword("USER")?;
+                let username = cursor.name("a username")?;
+                match cursor.keyword(&["PASSWORD", "ROLE"]).as_deref() {
+                    Some("PASSWORD") => Command::ALTERPASSWORD(username, cursor.name("a password")?),
+                    Some(_) => Command::ALTERROLE(username, Parser::role(cursor)?),
+                    None => return Err(cursor.error(cursor.position, "PASSWORD or ROLE")),
                 }
             }
-            Some(Token::WHICH) => {
-                if let Some(Token::IDENTIFIER(name)) = tokens.get(1) {
-                    Ok(Command::WHICH(name.clone()))
-                } else {
-                    Err(DatabaseError::SyntaxError("Missing Identifier".to_string()))
-                }
+            "LIST" => match cursor.keyword(&["USERS", "TOKENS", "COLLECTIONS"]).as_deref() {
+                Some("USERS") => Command::LISTUSERS(),
+                Some("TOKENS") => Command::LISTTOKENS(),
+                Some(_) => Command::LISTCOLLECTIONS(),
+                None => return Err(cursor.error(cursor.position, "USERS, TOKENS or COLLECTIONS")),
+            },
+            "UNLOCK" => {
+                cursor.expect_keyword("USER")?;
+                Command::UNLOCKUSER(cursor.name("a username")?)
+            }
+            _ => {
+                cursor.expect_keyword("COLLECTION")?;
+                let name = cursor.name("a collection")?;
+                cursor.expect_keyword("TO")?;
+                Command::RENAMECOLLECTION(name, cursor.name("the new name")?)
             }
-            _ => Err(DatabaseError::SyntaxError("Unknown command".to_string())),
+        };
+        cursor.end()?;
+        Ok(command)
+    }
+
+    // (CREATE|DROP) INDEX ON <collection> (<json.path>), spaces inside the parentheses are ignored
+    fn index(cursor: &mut Cursor, create: bool) -> Result<Command, DatabaseError> {
+        cursor.expect_keyword("ON")?;
+        let collection = cursor.name("a collection")?;
+        cursor.skip_whitespace();
+        let start = cursor.position;
+        let path = cursor.rest().strip_prefix('(').and_then(|rest| rest.find(')').map(|end| &rest[..end]))
+            .ok_or_else(|| cursor.error(start, "a path in parentheses"))?;
+        cursor.position += path.len() + 2;
+        let path: String = path.split_whitespace().collect();
+        if path.is_empty() {
+            return Err(cursor.error(start, "a path in parentheses"))
+        }
+        match create {
+            true => Ok(Command::CREATEINDEX(collection, path)),
+            false => Ok(Command::DROPINDEX(collection, path)),
         }
     }
 
-    fn lexer(line: &str) -> Result<Vec<Token>, DatabaseError> {
-        let result = Parser::lex_insert(line)?;
-        let mut results = Vec::new();
+    fn role(cursor: &mut Cursor) -> Result<Permissions, DatabaseError> {
+        cursor.skip_whitespace();
+        let at = cursor.position;
+        cursor.name("admin, user, guest or restricted")?.parse().map_err(|e| cursor.locate(at, e))
+    }
 
-        if result.is_some() {
-            let result = result.unwrap();
-            let token_a = match result.0.to_uppercase().as_str() {
-                "INSERT" => Token::INSERT,
-                "GET" => Token::GET,
-                "DELETE" => Token::DELETE,
-                "SELECT" => Token::SELECT,
-                "NEW" => Token::NEW,
-                "WHICH" => Token::WHICH,
-                _ => return Err(DatabaseError::SyntaxError("Unknown command".to_string())),
-            };
+    // SCAN [prefix] [LIMIT n] [AFTER cursor] and RANGE <from> <to> [LIMIT n] [AFTER cursor]. A
+    // prefix or key spelt like a clause has to be quoted
+    fn scan(cursor: &mut Cursor, command: &str) -> Result<Command, DatabaseError> {
+        let count = if command == "SCAN" { 1 } else { 2 };
+        let mut keys = Vec::new();
+        while keys.len() < count && !cursor.at_end()
+            && cursor.peek_word().is_none_or(|word| !word.eq_ignore_ascii_case("LIMIT") && !word.eq_ignore_ascii_case("AFTER")) {
+            keys.push(cursor.key()?);
+        }
+        if keys.len() < count && command == "RANGE" {
+            return Err(cursor.error(cursor.position, "a key"))
+        }
 
-            let token_b = Token::IDENTIFIER(result.1);
+        let (mut limit, mut after) = (None, None);
+        while let Some(clause) = cursor.keyword(&["LIMIT", "AFTER"]) {
+            match clause.as_str() {
+                "LIMIT" => limit = Some(cursor.number("a number after LIMIT")?),
+                _ => after = Some(cursor.name("a cursor after AFTER")?),
+            }
+        }
+        if !cursor.at_end() {
+            return Err(cursor.error(cursor.position, "LIMIT, AFTER or the end of the command"))
+        }
 
-            let token_c = Token::JSON(json!(result.2));
+        let mut keys = keys.into_iter();
+        match command {
+            "SCAN" => Ok(Command::SCAN(keys.next().unwrap_or(Key::Bare(String::new())), limit, after)),
+            _ => Ok(Command::RANGE(keys.next().unwrap(), keys.next().unwrap(), limit, after)),
+        }
+    }
 
-            results.push(token_a);
-            results.push(token_b);
-            results.push(token_c);
+    // GRANT <read|write|admin> ON <collection> TO [ROLE] <name> and REVOKE ... FROM [ROLE] <name>
+    fn grant(cursor: &mut Cursor, command: &str) -> Result<Command, DatabaseError> {
+        cursor.skip_whitespace();
+        let at = cursor.position;
+        let access: Access = cursor.name("read, write or admin")?.parse().map_err(|e| cursor.locate(at, e))?;
+        cursor.expect_keyword("ON")?;
+        let collection = cursor.name("a collection")?;
+        cursor.expect_keyword(if command == "GRANT" { "TO" } else { "FROM" })?;
+        let grantee = match cursor.keyword(&["ROLE"]) {
+            Some(_) => Grantee::Role(Parser::role(cursor)?),
+            None => Grantee::User(cursor.name("a username or ROLE")?),
+        };
+        match command {
+            "GRANT" => Ok(Command::GRANT(access, collection, grantee)),
+            _ => Ok(Command::REVOKE(access, collection, grantee)),
+        }
+    }
 
-            return Ok(results)
+    // CREATE TOKEN [FOR <user>] [EXPIRES <n>(m|h|d)|NEVER] [SCOPE read|write]
+    fn create_token(cursor: &mut Cursor) -> Result<Command, DatabaseError> {
+        let (mut owner, mut lifetime, mut read_only) = (None, None, false);
+        while let Some(option) = cursor.keyword(&["FOR", "EXPIRES", "SCOPE"]) {
+            cursor.skip_whitespace();
+            let at = cursor.position;
+            match option.as_str() {
+                "FOR" => owner = Some(cursor.name("a username")?),
+                "EXPIRES" => lifetime = Some(Parser::parse_lifetime(&cursor.name("<n>m, <n>h, <n>d or NEVER")?).map_err(|e| cursor.locate(at, e))?),
+                _ => read_only = match cursor.name("read or write")?.to_lowercase().as_str() {
+                    "read" => true,
+                    "write" => false,
+                    _ => return Err(cursor.error(at, "read or write")),
+                },
+            }
+        }
+        if !cursor.at_end() {
+            return Err(cursor.error(cursor.position, "FOR, EXPIRES, SCOPE or the end of the command"))
+        }
+        Ok(Command::CREATETOKEN(owner, lifetime, read_only))
+    }
 
+    // 90m, 12h, 30d or NEVER, in seconds
+    fn parse_lifetime(value: &str) -> Result<u64, DatabaseError> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Ok(u64::MAX)
+        }
+        let invalid = || DatabaseError::SyntaxError {
+            message: format!("found {}", value),
+            column: None,
+            expected: Some("a lifetime like 90m, 12h, 30d or NEVER".to_string()),
+        };
+        let unit = match value.chars().last().map(|unit| unit.to_ascii_lowercase()) {
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let count: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+        match count.checked_mul(unit) {
+            Some(0) | None => Err(invalid()),
+            Some(seconds) => Ok(seconds),
         }
-        Err(DatabaseError::SyntaxError("lexer".to_string()))
+    }
 
+    // AUDIT [USER <name>] [COLLECTION <name>] [OPERATION <name>] [KEY <key>] [LIMIT n]
+    fn audit(cursor: &mut Cursor) -> Result<Command, DatabaseError> {
+        let mut filter = AuditFilter::default();
+        while let Some(field) = cursor.keyword(&["USER", "COLLECTION", "OPERATION", "KEY", "LIMIT"]) {
+            match field.as_str() {
+                "USER" => filter.user = Some(cursor.name("a username")?),
+                "COLLECTION" => filter.collection = Some(cursor.name("a collection")?),
+                "OPERATION" => filter.operation = Some(cursor.name("an operation")?),
+                "KEY" => filter.key = Some(cursor.name("a key")?),
+                _ => filter.limit = Some(cursor.number("a number after LIMIT")?),
+            }
+        }
+        if !cursor.at_end() {
+            return Err(cursor.error(cursor.position, "USER, COLLECTION, OPERATION, KEY, LIMIT or the end of the command"))
+        }
+        Ok(Command::AUDIT(filter))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::{json, Value};
+
+    use crate::audit::AuditFilter;
+    use crate::auth::{Access, Grantee, Permissions};
+    use crate::errors::DatabaseError;
+    use crate::parser::{Command, Key, Parser};
+    use crate::update::Update;
+
+    #[test]
+    fn index_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("CREATE INDEX ON users (address.city)"),
+            Ok(Command::CREATEINDEX(collection, path)) if collection == "users" && path == "address.city"));
+        assert!(matches!(parser.get_command("drop index on users ( email )"),
+            Ok(Command::DROPINDEX(collection, path)) if collection == "users" && path == "email"));
+        assert!(parser.get_command("CREATE INDEX users email").is_err());
+
+        assert!(matches!(parser.get_command("FIND users WHERE email = \"a@x\""),
+            Ok(Command::FIND(query)) if query.collection.as_deref() == Some("users")
+                && query.conditions[0].path == "email" && query.conditions[0].value == json!("a@x")));
+        assert!(parser.get_command("FIND users WHERE email = a@x").is_err());
     }
 
-    fn lex_insert(input: &str) -> Result<Option<(String, String, Option<serde_json::Value>)>, DatabaseError> {
-        let mut parts = input.trim().splitn(3, ' '); 
-        let cmd = parts.next().ok_or(DatabaseError::SyntaxError("".to_string()))?;
-        let collection = parts.next().ok_or(DatabaseError::SyntaxError("".to_string()))?;
-        let json_str = parts.next();
+    #[test]
+    fn scan_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("SCAN order:2024:"),
+            Ok(Command::SCAN(prefix, None, None)) if prefix == Key::Bare("order:2024:".to_string())));
+        assert!(matches!(parser.get_command("scan limit 5"),
+            Ok(Command::SCAN(prefix, Some(5), None)) if prefix == Key::Bare(String::new())));
+        assert!(matches!(parser.get_command("RANGE a c LIMIT 2 AFTER b"),
+            Ok(Command::RANGE(from, to, Some(2), Some(after))) if from == Key::Bare("a".to_string()) && to == Key::Bare("c".to_string()) && after == "b"));
+        assert!(parser.get_command("RANGE a").is_err());
+        assert!(parser.get_command("SCAN a LIMIT ten").is_err());
+        assert!(parser.get_command("SCAN a LIMIT").is_err());
+    }
 
-        let mut json_value = None;
+    #[test]
+    fn grant_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("GRANT read ON orders TO contractor"),
+            Ok(Command::GRANT(Access::Read, collection, Grantee::User(user))) if collection == "orders" && user == "contractor"));
+        assert!(matches!(parser.get_command("revoke write on orders from role guest"),
+            Ok(Command::REVOKE(Access::Write, collection, Grantee::Role(Permissions::Guest()))) if collection == "orders"));
+        assert!(parser.get_command("GRANT everything ON orders TO contractor").is_err());
+        assert!(parser.get_command("GRANT read ON orders FROM contractor").is_err());
+        assert!(parser.get_command("GRANT read ON orders TO ROLE nobody").is_err());
+    }
 
-        if json_str.is_some() {
-            json_value = serde_json::from_str(json_str.unwrap()).ok();
-        } 
-        Ok(Some((cmd.to_string(), collection.to_string(), json_value)))
+    #[test]
+    fn user_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("CREATE USER alice PASSWORD secret"),
+            Ok(Command::CREATEUSER(user, password, Permissions::User())) if user == "alice" && password == "secret"));
+        assert!(matches!(parser.get_command("create user bob password pw role guest"),
+            Ok(Command::CREATEUSER(user, _, Permissions::Guest())) if user == "bob"));
+        assert!(matches!(parser.get_command("ALTER USER alice ROLE admin"), Ok(Command::ALTERROLE(_, Permissions::Admin()))));
+        assert!(matches!(parser.get_command("ALTER USER alice PASSWORD Other"), Ok(Command::ALTERPASSWORD(_, password)) if password == "Other"));
+        assert!(matches!(parser.get_command("DROP USER alice"), Ok(Command::DROPUSER(user)) if user == "alice"));
+        assert!(matches!(parser.get_command("list users"), Ok(Command::LISTUSERS())));
+        assert!(matches!(parser.get_command("UNLOCK USER alice"), Ok(Command::UNLOCKUSER(user)) if user == "alice"));
+        assert!(parser.get_command("CREATE USER alice").is_err());
+        assert!(parser.get_command("ALTER USER alice ROLE owner").is_err());
+        // still an index command
+        assert!(matches!(parser.get_command("DROP INDEX ON users (email)"), Ok(Command::DROPINDEX(..))));
     }
-}
 
+    #[test]
+    fn audit_command() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("AUDIT"), Ok(Command::AUDIT(filter)) if filter == AuditFilter::default()));
+        assert!(matches!(parser.get_command("audit operation DELETE key order:1 limit 5"),
+            Ok(Command::AUDIT(filter)) if filter.operation.as_deref() == Some("DELETE")
+                && filter.key.as_deref() == Some("order:1") && filter.limit == Some(5)));
+        assert!(parser.get_command("AUDIT USER").is_err());
+        assert!(parser.get_command("AUDIT WHO alice").is_err());
+    }
+
+    #[test]
+    fn update_command() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("UPDATE counter {\"$inc\": {\"hits\": 1}}"),
+            Ok(Command::UPDATE(key, Update::Operators(_))) if key == Key::Bare("counter".to_string())));
+        assert!(matches!(parser.get_command("update a {\"name\": null}"), Ok(Command::UPDATE(_, Update::Merge(_)))));
+        assert!(parser.get_command("UPDATE a").is_err());
+        assert!(parser.get_command("UPDATE a {bad").is_err());
+    }
+
+    #[test]
+    fn schema_command() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("SCHEMA users"), Ok(Command::SCHEMA(collection, None)) if collection == "users"));
+        assert!(matches!(parser.get_command("schema users {\"required\": [\"email\"],  \"type\": \"object\"}"),
+            Ok(Command::SCHEMA(_, Some(schema))) if schema == json!({"required": ["email"], "type": "object"})));
+        assert!(matches!(parser.get_command("SCHEMA users null"), Ok(Command::SCHEMA(_, Some(Value::Null)))));
+        assert!(parser.get_command("SCHEMA users {\"required\":").is_err());
+        assert!(parser.get_command("SCHEMA").is_err());
+    }
+
+    #[test]
+    fn token_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("CREATE TOKEN"), Ok(Command::CREATETOKEN(None, None, false))));
+        assert!(matches!(parser.get_command("create token for ci expires 12h scope read"),
+            Ok(Command::CREATETOKEN(Some(owner), Some(43200), true)) if owner == "ci"));
+        assert!(matches!(parser.get_command("CREATE TOKEN EXPIRES never"), Ok(Command::CREATETOKEN(None, Some(u64::MAX), false))));
+        assert!(matches!(parser.get_command("LIST TOKENS"), Ok(Command::LISTTOKENS())));
+        assert!(matches!(parser.get_command("REVOKE TOKEN 0a1b"), Ok(Command::REVOKETOKEN(id)) if id == "0a1b"));
+        assert!(parser.get_command("CREATE TOKEN EXPIRES 0d").is_err());
+        assert!(parser.get_command("CREATE TOKEN EXPIRES soon").is_err());
+        assert!(parser.get_command("CREATE TOKEN SCOPE admin").is_err());
+        // still a grant
+        assert!(matches!(parser.get_command("REVOKE read ON orders FROM ci"), Ok(Command::REVOKE(..))));
+    }
+
+    #[test]
+    fn collection_commands() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("list collections"), Ok(Command::LISTCOLLECTIONS())));
+        assert!(matches!(parser.get_command("DROP COLLECTION orders"), Ok(Command::DROPCOLLECTION(name)) if name == "orders"));
+        assert!(matches!(parser.get_command("rename collection Orders to archive"),
+            Ok(Command::RENAMECOLLECTION(name, to)) if name == "Orders" && to == "archive"));
+        assert!(parser.get_command("RENAME COLLECTION orders archive").is_err());
+        // still an index
+        assert!(matches!(parser.get_command("DROP INDEX ON orders (total)"), Ok(Command::DROPINDEX(..))));
+    }
+
+    #[test]
+    fn quoted_keys_and_multi_line_values() {
+        let parser = Parser::new();
+        assert!(matches!(parser.get_command("INSERT \"order 1\" {\"total\": 5}"),
+            Ok(Command::INSERT(key, value)) if key == Key::Quoted("order 1".to_string()) && value == json!({"total": 5})));
+        assert!(matches!(parser.get_command("get \"tab\\there \\\"quoted\\\" \\u00e9\""),
+            Ok(Command::GET(key)) if key == Key::Quoted("tab\there \"quoted\" é".to_string())));
+        assert!(matches!(parser.get_command("INSERT a {\n  \"b\": [1,\n    2]\n}\n"), Ok(Command::INSERT(_, value)) if value == json!({"b": [1, 2]})));
+        assert!(matches!(parser.get_command("SCAN \"LIMIT\" LIMIT 2"), Ok(Command::SCAN(prefix, Some(2), None)) if prefix == Key::Quoted("LIMIT".to_string())));
+        // quoting keeps a dotted key in the selected collection
+        assert!(matches!(parser.get_command("GET orders.2024"), Ok(Command::GET(Key::Bare(key))) if key == "orders.2024"));
+        assert!(matches!(parser.get_command("GET \"orders.2024\""), Ok(Command::GET(Key::Quoted(key))) if key == "orders.2024"));
+
+        // bad JSON is an error rather than null, and so is a missing value
+        let syntax_error = |input: &str| match parser.get_command(input) {
+            Err(DatabaseError::SyntaxError { column, expected, .. }) => (column.unwrap(), expected.unwrap_or_default()),
+            other => panic!("expected a syntax error, got {:?}", other),
+        };
+        assert_eq!(syntax_error("INSERT k {bad}"), (11, "a JSON value".to_string()));
+        assert_eq!(syntax_error("INSERT k"), (9, "a JSON value".to_string()));
+        assert_eq!(syntax_error("INSERT k 1 2"), (12, "the end of the command".to_string()));
+        assert_eq!(syntax_error("INSERT \"unclosed"), (17, "a key to be finished".to_string()));
+        assert_eq!(syntax_error("FETCH k"), (1, "a command".to_string()));
+        assert_eq!(syntax_error("CREATE USER alice PASSWORD pw ROLE owner").0, 36);
+        assert_eq!(syntax_error("FIND users WHERE age > old"), (24, "a JSON value, strings need quotes".to_string()));
+
+        // what a line at a time front end needs to know to keep reading
+        assert!(parser.is_unfinished("INSERT a {\"b\": [1,"));
+        assert!(parser.is_unfinished("GET \"half a key"));
+        assert!(parser.is_unfinished("FIND WHERE tags CONTAINS [\"a\","));
+        assert!(!parser.is_unfinished("INSERT a {\"b\" 1}"));
+        assert!(!parser.is_unfinished("INSERT a"));
+    }
+}
@@ -0,0 +1,329 @@
+use std::cmp::Ordering;
+
+use serde_json::{json, Map, Value};
+
+use crate::collections::{lookup, Collection};
+use crate::errors::DatabaseError;
+
+// FIND [collection] [WHERE <path> <op> <value> [AND ...]] [ORDER BY <path> [ASC|DESC]] [LIMIT n] [FIELDS a,b.c]
+//
+// Without a collection the selected one is searched. Operators are =, !=, >, >=, <, <= and
+// CONTAINS, which matches an array holding the value or a string holding the substring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub collection: Option<String>,
+    pub conditions: Vec<Condition>,
+    pub order_by: Option<(String, bool)>,
+    pub limit: Option<usize>,
+    pub fields: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub path: String,
+    pub operator: Operator,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+const CLAUSES: [&str; 4] = ["WHERE", "ORDER", "LIMIT", "FIELDS"];
+
+impl Query {
+    // Parses everything after the FIND keyword, error columns count from the start of it
+    pub fn parse(input: &str) -> Result<Query, DatabaseError> {
+        let tokens = Query::tokenize(input)?;
+        let end = input.chars().count() + 1;
+        let mut tokens = tokens.iter().map(|(token, column)| (token.as_str(), *column)).peekable();
+        let mut query = Query { collection: None, conditions: Vec::new(), order_by: None, limit: None, fields: None };
+        let is_clause = |token: &(&str, usize)| CLAUSES.contains(&token.0.to_uppercase().as_str());
+
+        if let Some((first, _)) = tokens.next_if(|first| !is_clause(first)) {
+            query.collection = Some(first.to_string());
+        }
+
+        while let Some((clause, column)) = tokens.next() {
+            match clause.to_uppercase().as_str() {
+                "WHERE" => loop {
+                    let (path, _) = tokens.next().ok_or(Query::expected(end, None, "a path after WHERE"))?;
+                    let (operator, column) = tokens.next().unwrap_or(("", end));
+                    let operator = match operator.to_uppercase().as_str() {
+                        "=" => Operator::Eq,
+                        "!=" => Operator::Ne,
+                        ">" => Operator::Gt,
+                        ">=" => Operator::Ge,
+                        "<" => Operator::Lt,
+                        "<=" => Operator::Le,
+                        "CONTAINS" => Operator::Contains,
+                        other => return Err(Query::expected(column, Some(other), &format!("an operator after {}", path))),
+                    };
+                    let (value, column) = tokens.next().ok_or(Query::expected(end, None, &format!("a value after {}", path)))?;
+                    let value = serde_json::from_str(value)
+                        .map_err(|_| Query::expected(column, Some(value), "a JSON value, strings need quotes"))?;
+                    query.conditions.push(Condition { path: path.to_string(), operator, value });
+
+                    match tokens.peek() {
+                        Some((next, _)) if next.eq_ignore_ascii_case("AND") => { tokens.next(); }
+                        _ => break,
+                    }
+                },
+                "ORDER" => {
+                    match tokens.next() {
+                        Some((by, _)) if by.eq_ignore_ascii_case("BY") => (),
+                        Some((other, column)) => return Err(Query::expected(column, Some(other), "BY after ORDER")),
+                        None => return Err(Query::expected(end, None, "BY after ORDER")),
+                    }
+                    let (path, _) = tokens.next().ok_or(Query::expected(end, None, "a path after ORDER BY"))?;
+                    let descending = match tokens.peek().map(|(word, _)| word.to_uppercase()).as_deref() {
+                        Some("DESC") => { tokens.next(); true }
+                        Some("ASC") => { tokens.next(); false }
+                        _ => false,
+                    };
+                    query.order_by = Some((path.to_string(), descending));
+                }
+                "LIMIT" => {
+                    let (limit, column) = tokens.next().ok_or(Query::expected(end, None, "a number after LIMIT"))?;
+                    query.limit = Some(limit.parse().map_err(|_| Query::expected(column, Some(limit), "a number after LIMIT"))?);
+                }
+                "FIELDS" => {
+                    let mut fields = Vec::new();
+                    while let Some((field, _)) = tokens.next_if(|token| !is_clause(token)) {
+                        fields.extend(field.split(',').filter(|field| !field.is_empty()).map(|field| field.to_string()));
+                    }
+                    if fields.is_empty() {
+                        return Err(Query::expected(column + clause.chars().count(), None, "field names after FIELDS"))
+                    }
+                    query.fields = Some(fields);
+                }
+                other => return Err(Query::expected(column, Some(other), "WHERE, ORDER BY, LIMIT or FIELDS")),
+            }
+        }
+        Ok(query)
+    }
+
+    // Splits on whitespace, keeping quoted strings and bracketed JSON whole and operators apart
+    fn tokenize(input: &str) -> Result<Vec<(String, usize)>, DatabaseError> {
+        let chars: Vec<char> = input.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let start = i;
+            match chars[i] {
+                c if c.is_whitespace() => { i += 1; continue }
+                '"' => {
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        i += if chars[i] == '\\' { 2 } else { 1 };
+                    }
+                    if i >= chars.len() {
+                        return Err(Query::expected(start + 1, Some(&chars[start..].iter().collect::<String>()), "a closing quote"))
+                    }
+                    i += 1;
+                }
+                '[' | '{' => {
+                    let mut depth = 0;
+                    let mut in_string = false;
+                    while i < chars.len() {
+                        match chars[i] {
+                            '\\' if in_string => i += 1,
+                            '"' => in_string = !in_string,
+                            '[' | '{' if !in_string => depth += 1,
+                            ']' | '}' if !in_string => depth -= 1,
+                            _ => (),
+                        }
+                        i += 1;
+                        if depth == 0 {
+                            break
+                        }
+                    }
+                    if depth != 0 {
+                        return Err(Query::expected(start + 1, Some(&chars[start..].iter().collect::<String>()), "a closing bracket"))
+                    }
+                }
+                '=' => i += 1,
+                '!' | '<' | '>' => {
+                    i += 1;
+                    if i < chars.len() && chars[i] == '=' {
+                        i += 1;
+                    }
+                }
+                _ => {
+                    while i < chars.len() && !chars[i].is_whitespace() && !"=!<>\"".contains(chars[i]) {
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push((chars[start..i].iter().collect(), start + 1));
+        }
+        Ok(tokens)
+    }
+
+    // The column is the one the token starts at, or one past the end when the query ran out
+    fn expected(column: usize, found: Option<&str>, what: &str) -> DatabaseError {
+        let message = match found {
+            Some("") | None => "found the end of the command".to_string(),
+            Some(found) => format!("found {}", found.split_whitespace().next().unwrap_or(found)),
+        };
+        DatabaseError::SyntaxError { message, column: Some(column), expected: Some(what.to_string()) }
+    }
+
+    // Matching documents as [{"key": ..., "value": ...}] after sorting, limiting and projecting
+    pub(crate) fn run(&self, collection: &Collection) -> Value {
+        // an equality on an indexed path narrows things down before the rest of the filter runs
+        let indexed = self.conditions.iter()
+            .find(|condition| condition.operator == Operator::Eq && collection.index_paths().contains(&condition.path));
+        let candidates = match indexed {
+            Some(condition) => collection.find(&condition.path, &condition.value),
+            None => collection.documents(),
+        };
+
+        let mut found: Vec<(String, Value)> = candidates.into_iter()
+            .filter(|(_, document)| self.conditions.iter().all(|condition| condition.matches(document)))
+            .collect();
+
+        if let Some((path, descending)) = &self.order_by {
+            found.sort_by(|(_, a), (_, b)| {
+                let ordering = match (lookup(a, path), lookup(b, path)) {
+                    (Some(a), Some(b)) => compare(a, b),
+                    // documents without the field go last either way
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                };
+                if *descending { ordering.reverse() } else { ordering }
+            });
+        }
+        if let Some(limit) = self.limit {
+            found.truncate(limit);
+        }
+
+        Value::Array(found.into_iter().map(|(key, document)| {
+            let value = match &self.fields {
+                Some(fields) => Value::Object(fields.iter()
+                    .filter_map(|field| lookup(&document, field).map(|value| (field.clone(), value.clone())))
+                    .collect::<Map<String, Value>>()),
+                None => document,
+            };
+            json!({ "key": key, "value": value })
+        }).collect())
+    }
+}
+
+impl Condition {
+    fn matches(&self, document: &Value) -> bool {
+        let Some(field) = lookup(document, &self.path) else {
+            return self.operator == Operator::Ne
+        };
+        match self.operator {
+            Operator::Eq => field == &self.value,
+            Operator::Ne => field != &self.value,
+            Operator::Contains => match (field, &self.value) {
+                (Value::Array(items), value) => items.contains(value),
+                (Value::String(text), Value::String(part)) => text.contains(part.as_str()),
+                _ => false,
+            },
+            operator => {
+                // ordering only means something between values of the same type
+                if rank(field) != rank(&self.value) {
+                    return false
+                }
+                let ordering = compare(field, &self.value);
+                match operator {
+                    Operator::Gt => ordering == Ordering::Greater,
+                    Operator::Ge => ordering != Ordering::Less,
+                    Operator::Lt => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                }
+            }
+        }
+    }
+}
+
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+// Numbers by value, strings alphabetically, false before true and different types by rank
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::collections::Collection;
+    use crate::query::{Operator, Query};
+
+    fn people() -> Collection {
+        let mut collection = Collection::new("people".to_string());
+        collection.insert("a".to_string(), json!({"name": "Cleo", "age": 41, "tags": ["x"], "address": {"city": "Oslo"}}));
+        collection.insert("b".to_string(), json!({"name": "Ana", "age": 35, "tags": ["x", "y"], "email": "ana@x"}));
+        collection.insert("c".to_string(), json!({"name": "Bo", "age": 20, "tags": ["x"], "active": true}));
+        collection.insert("d".to_string(), json!({"name": "Dee", "age": "unknown"}));
+        collection
+    }
+
+    #[test]
+    fn parse_query() {
+        let query = Query::parse("people WHERE age > 30 AND tags CONTAINS \"a b\" ORDER BY name DESC LIMIT 10 FIELDS name, email").unwrap();
+        assert_eq!(query.collection.as_deref(), Some("people"));
+        assert_eq!(query.conditions.len(), 2);
+        assert_eq!(query.conditions[1].operator, Operator::Contains);
+        assert_eq!(query.conditions[1].value, json!("a b"));
+        assert_eq!(query.order_by, Some(("name".to_string(), true)));
+        assert_eq!(query.limit, Some(10));
+        assert_eq!(query.fields, Some(vec!["name".to_string(), "email".to_string()]));
+
+        assert_eq!(Query::parse("WHERE age>=30").unwrap().collection, None);
+        assert!(Query::parse("WHERE age > thirty").is_err());
+        assert!(Query::parse("ORDER name").is_err());
+    }
+
+    #[test]
+    fn run_query() {
+        let collection = people();
+        let run = |query: &str| Query::parse(query).unwrap().run(&collection);
+
+        assert_eq!(run("WHERE age > 30 AND tags CONTAINS \"x\" ORDER BY name FIELDS name"),
+            json!([{"key": "b", "value": {"name": "Ana"}}, {"key": "a", "value": {"name": "Cleo"}}]));
+        assert_eq!(run("WHERE address.city = \"Oslo\" FIELDS address.city"),
+            json!([{"key": "a", "value": {"address.city": "Oslo"}}]));
+        assert_eq!(run("WHERE active = true FIELDS name"), json!([{"key": "c", "value": {"name": "Bo"}}]));
+        assert_eq!(run("WHERE email CONTAINS \"@\" FIELDS name"), json!([{"key": "b", "value": {"name": "Ana"}}]));
+        assert_eq!(run("ORDER BY age DESC LIMIT 2 FIELDS age"),
+            json!([{"key": "d", "value": {"age": "unknown"}}, {"key": "a", "value": {"age": 41}}]));
+    }
+
+    #[test]
+    fn indexed_query_matches_scan() {
+        let mut collection = people();
+        let query = Query::parse("WHERE age = 35 AND name != \"Bo\"").unwrap();
+        let scanned = query.run(&collection);
+        collection.create_index("age".to_string());
+        assert_eq!(query.run(&collection), scanned);
+    }
+}
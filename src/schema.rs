@@ -0,0 +1,271 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use std::collections::BTreeMap;
+
+use crate::errors::DatabaseError;
+
+// A JSON Schema style description of what a collection's documents have to look like. Only the
+// keywords below are understood and anything else is refused, so a typo can't quietly switch a
+// rule off:
+//
+//   type                 "object", "array", "string", "number", "integer", "boolean", "null" or a list of them
+//   enum                 the value has to be one of these
+//   minimum, maximum, exclusiveMinimum, exclusiveMaximum
+//   minLength, maxLength, pattern
+//   properties, required, additionalProperties (true or false)
+//   items, minItems, maxItems
+#[derive(Debug)]
+pub struct Schema {
+    // as it was given, which is what gets logged and written with the snapshot
+    source: Value,
+    rules: Rules,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    types: Option<Vec<String>>,
+    enumeration: Option<Vec<Value>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    properties: BTreeMap<String, Rules>,
+    required: Vec<String>,
+    additional_properties: bool,
+    items: Option<Box<Rules>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+const TYPES: [&str; 7] = ["object", "array", "string", "number", "integer", "boolean", "null"];
+
+impl Schema {
+    pub fn parse(source: Value) -> Result<Schema, DatabaseError> {
+        let rules = Rules::parse(&source, "schema")?;
+        Ok(Schema { source, rules })
+    }
+
+    pub fn source(&self) -> &Value {
+        &self.source
+    }
+
+    // The first rule the document breaks, with the path to where in the document it broke it
+    pub fn validate(&self, document: &Value) -> Result<(), DatabaseError> {
+        self.rules.validate(document, "$")
+    }
+}
+
+impl Rules {
+    fn parse(schema: &Value, at: &str) -> Result<Rules, DatabaseError> {
+        let invalid = |reason: String| DatabaseError::syntax(format!("{} {}", at, reason));
+        let Value::Object(schema) = schema else {
+            return Err(invalid("should be an object".to_string()))
+        };
+
+        let number = |key: &str| match schema.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_f64().map(Some).ok_or_else(|| invalid(format!("{} should be a number", key))),
+        };
+        let count = |key: &str| match schema.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_u64().map(|count| Some(count as usize)).ok_or_else(|| invalid(format!("{} should be a whole number", key))),
+        };
+
+        let mut rules = Rules { additional_properties: true, ..Default::default() };
+        for (key, value) in schema {
+            match key.as_str() {
+                "type" => {
+                    let types: Vec<String> = match value {
+                        Value::String(name) => vec![name.clone()],
+                        Value::Array(names) => names.iter().filter_map(|name| name.as_str().map(String::from)).collect(),
+                        _ => Vec::new(),
+                    };
+                    if types.is_empty() || types.iter().any(|name| !TYPES.contains(&name.as_str())) {
+                        return Err(invalid(format!("type should be one or a list of {}", TYPES.join(", "))))
+                    }
+                    rules.types = Some(types);
+                }
+                "enum" => rules.enumeration = Some(value.as_array().cloned().ok_or_else(|| invalid("enum should be an array".to_string()))?),
+                "minimum" => rules.minimum = number(key)?,
+                "maximum" => rules.maximum = number(key)?,
+                "exclusiveMinimum" => rules.exclusive_minimum = number(key)?,
+                "exclusiveMaximum" => rules.exclusive_maximum = number(key)?,
+                "minLength" => rules.min_length = count(key)?,
+                "maxLength" => rules.max_length = count(key)?,
+                "minItems" => rules.min_items = count(key)?,
+                "maxItems" => rules.max_items = count(key)?,
+                "pattern" => {
+                    let pattern = value.as_str().ok_or_else(|| invalid("pattern should be a string".to_string()))?;
+                    rules.pattern = Some(Regex::new(pattern).map_err(|e| invalid(format!("pattern doesn't compile: {}", e)))?);
+                }
+                "properties" => {
+                    let properties = value.as_object().ok_or_else(|| invalid("properties should be an object".to_string()))?;
+                    for (name, property) in properties {
+                        rules.properties.insert(name.clone(), Rules::parse(property, &format!("{}.{}", at, name))?);
+                    }
+                }
+                "required" => {
+                    rules.required = value.as_array()
+                        .and_then(|names| names.iter().map(|name| name.as_str().map(String::from)).collect())
+                        .ok_or_else(|| invalid("required should be an array of field names".to_string()))?;
+                }
+                "additionalProperties" => {
+                    rules.additional_properties = value.as_bool().ok_or_else(|| invalid("additionalProperties should be true or false".to_string()))?;
+                }
+                "items" => rules.items = Some(Box::new(Rules::parse(value, &format!("{}[]", at))?)),
+                other => return Err(invalid(format!("has an unknown keyword {}", other))),
+            }
+        }
+        Ok(rules)
+    }
+
+    fn validate(&self, value: &Value, path: &str) -> Result<(), DatabaseError> {
+        let fail = |reason: String| Err(DatabaseError::ValidationError { path: path.to_string(), reason });
+
+        if let Some(types) = &self.types && !types.iter().any(|name| Rules::is_type(value, name)) {
+            return fail(format!("expected {}, found {}", types.join(" or "), Rules::type_name(value)))
+        }
+        if let Some(enumeration) = &self.enumeration && !enumeration.contains(value) {
+            let allowed: Vec<String> = enumeration.iter().map(|value| value.to_string()).collect();
+            return fail(format!("{} isn't one of {}", value, allowed.join(", ")))
+        }
+
+        if let Some(number) = value.as_f64() {
+            let bounds = [
+                (self.minimum, number >= self.minimum.unwrap_or(f64::MIN), "at least"),
+                (self.maximum, number <= self.maximum.unwrap_or(f64::MAX), "at most"),
+                (self.exclusive_minimum, number > self.exclusive_minimum.unwrap_or(f64::MIN), "more than"),
+                (self.exclusive_maximum, number < self.exclusive_maximum.unwrap_or(f64::MAX), "less than"),
+            ];
+            for (bound, within, description) in bounds {
+                if let Some(bound) = bound && !within {
+                    return fail(format!("{} should be {} {}", number, description, bound))
+                }
+            }
+        }
+
+        if let Value::String(string) = value {
+            let length = string.chars().count();
+            if let Some(min) = self.min_length && length < min {
+                return fail(format!("should be at least {} characters long", min))
+            }
+            if let Some(max) = self.max_length && length > max {
+                return fail(format!("should be at most {} characters long", max))
+            }
+            if let Some(pattern) = &self.pattern && !pattern.is_match(string) {
+                return fail(format!("{:?} doesn't match {}", string, pattern.as_str()))
+            }
+        }
+
+        if let Value::Object(fields) = value {
+            self.validate_fields(fields, path)?;
+        }
+
+        if let Value::Array(items) = value {
+            if let Some(min) = self.min_items && items.len() < min {
+                return fail(format!("should have at least {} items", min))
+            }
+            if let Some(max) = self.max_items && items.len() > max {
+                return fail(format!("should have at most {} items", max))
+            }
+            if let Some(rules) = &self.items {
+                for (i, item) in items.iter().enumerate() {
+                    rules.validate(item, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_fields(&self, fields: &Map<String, Value>, path: &str) -> Result<(), DatabaseError> {
+        for name in &self.required {
+            if !fields.contains_key(name) {
+                return Err(DatabaseError::ValidationError { path: format!("{}.{}", path, name), reason: "is required".to_string() })
+            }
+        }
+        for (name, field) in fields {
+            let field_path = format!("{}.{}", path, name);
+            match self.properties.get(name) {
+                Some(rules) => rules.validate(field, &field_path)?,
+                None if !self.additional_properties => {
+                    return Err(DatabaseError::ValidationError { path: field_path, reason: "isn't allowed".to_string() })
+                }
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_type(value: &Value, name: &str) -> bool {
+        match name {
+            // 1.0 counts as an integer, the same as it does in JSON Schema
+            "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+            name => Rules::type_name(value) == name || (name == "number" && value.is_number()),
+        }
+    }
+
+    fn type_name(value: &Value) -> &'static str {
+        match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(number) if number.is_f64() => "number",
+            Value::Number(_) => "integer",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::errors::DatabaseError;
+    use crate::schema::Schema;
+
+    #[test]
+    fn schema_names_the_failing_path() {
+        let schema = Schema::parse(json!({
+            "type": "object",
+            "required": ["email", "address"],
+            "properties": {
+                "email": {"type": "string", "pattern": "^[^@]+@[^@]+$"},
+                "age": {"type": "integer", "minimum": 0, "maximum": 150},
+                "status": {"enum": ["active", "banned"]},
+                "address": {
+                    "type": "object",
+                    "required": ["city"],
+                    "properties": {"city": {"type": "string", "minLength": 1}},
+                },
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 3},
+            },
+        })).unwrap();
+
+        let good = json!({"email": "a@x", "age": 30, "status": "active", "address": {"city": "Oslo"}, "tags": ["a"]});
+        assert!(schema.validate(&good).is_ok());
+
+        let failing_path = |document: serde_json::Value| match schema.validate(&document) {
+            Err(DatabaseError::ValidationError { path, .. }) => path,
+            other => panic!("expected a validation error, got {:?}", other),
+        };
+        assert_eq!(failing_path(json!({"email": "a@x"})), "$.address");
+        assert_eq!(failing_path(json!({"email": "nope", "address": {"city": "Oslo"}})), "$.email");
+        assert_eq!(failing_path(json!({"email": "a@x", "age": 30.5, "address": {"city": "Oslo"}})), "$.age");
+        assert_eq!(failing_path(json!({"email": "a@x", "age": -1, "address": {"city": "Oslo"}})), "$.age");
+        assert_eq!(failing_path(json!({"email": "a@x", "status": "gone", "address": {"city": "Oslo"}})), "$.status");
+        assert_eq!(failing_path(json!({"email": "a@x", "address": {"city": ""}})), "$.address.city");
+        assert_eq!(failing_path(json!({"email": "a@x", "address": {"city": "Oslo"}, "tags": ["a", 2]})), "$.tags[1]");
+        assert_eq!(failing_path(json!("just a string")), "$");
+
+        // mistakes in the schema itself are caught when it's set
+        assert!(Schema::parse(json!({"type": "text"})).is_err());
+        assert!(Schema::parse(json!({"properties": {"age": {"minimun": 0}}})).is_err());
+        assert!(Schema::parse(json!({"pattern": "("})).is_err());
+    }
+}
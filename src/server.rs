@@ -0,0 +1,193 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use serde_json::{json, Value};
+
+use crate::database::{Database, Response};
+use crate::errors::DatabaseError;
+use crate::parser::Parser;
+use crate::session::Session;
+
+// Line based protocol, every line a client sends is one command in the same language as the
+// REPL and every reply is a single line of JSON:
+//
+//   {"ok": true, "value": ...}      {"ok": true, "message": "..."}      {"ok": false, "error": "..."}
+//
+// A connection has to send LOGIN <username> <password> before anything else and QUIT or EXIT
+// closes it. Each connection has its own session, selected collection and transaction.
+pub struct Server {
+    listener: TcpListener,
+    database: Arc<Mutex<Database>>,
+}
+
+impl Server {
+    pub fn bind(database: Database, address: &str) -> Result<Server, DatabaseError> {
+        let listener = TcpListener::bind(address)?;
+        Ok(Server { listener, database: Arc::new(Mutex::new(database)) })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DatabaseError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn run(&self) -> Result<(), DatabaseError> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("{}", DatabaseError::from(e));
+                    continue
+                }
+            };
+
+            let database = Arc::clone(&self.database);
+            thread::spawn(move || {
+                if let Err(e) = Server::handle_connection(stream, &database) {
+                    eprintln!("{}", e);
+                }
+
+                // every write is already in the WAL, this just keeps the WAL from growing forever.
+                // A connection that only read has nothing to save
+                let mut database = database.lock().unwrap();
+                if database.has_unsaved_changes() && let Err(e) = database.checkpoint() {
+                    eprintln!("{}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(stream: TcpStream, database: &Mutex<Database>) -> Result<(), DatabaseError> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let parser = Parser::new();
+        let mut session: Option<Session> = None;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break
+            }
+            // a command with JSON over several lines gets its reply once it's finished
+            while session.is_some() && parser.is_unfinished(&line) {
+                if reader.read_line(&mut line)? == 0 {
+                    break
+                }
+            }
+
+            let input = line.trim();
+            if input.is_empty() {
+                continue
+            }
+            if input.to_uppercase().eq("EXIT") || input.to_uppercase().eq("QUIT") {
+                break
+            }
+
+            let result = match session.as_mut() {
+                Some(session) => parser.get_command(input)
+                    .and_then(|command| database.lock().unwrap().execute(session, command)),
+                None => Server::login(input, database).map(|new_session| {
+                    let message = format!("Logged in as {}", new_session.user);
+                    session = Some(new_session);
+                    Response::Message(message)
+                }),
+            };
+
+            writeln!(writer, "{}", Server::encode(result))?;
+        }
+        Ok(())
+    }
+
+    fn login(input: &str, database: &Mutex<Database>) -> Result<Session, DatabaseError> {
+        let mut parts = input.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(command), Some(username), Some(password)) if command.to_uppercase() == "LOGIN" => {
+                // bcrypt is slow, so the password is checked without holding up other connections
+                let mut attempt = database.lock().unwrap().begin_login(username.to_string(), password.to_string());
+                attempt.check();
+                database.lock().unwrap().finish_login(attempt)
+            }
+            _ => Err(DatabaseError::UserError("LOGIN <username> <password> to access the database".to_string())),
+        }
+    }
+
+    pub fn encode(result: Result<Response, DatabaseError>) -> Value {
+        match result {
+            Ok(Response::Value(value)) => json!({ "ok": true, "value": value }),
+            Ok(Response::Message(message)) => json!({ "ok": true, "message": message }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tempdir::TempDir;
+
+    use crate::auth::Permissions;
+    use crate::database::Database;
+    use crate::server::Server;
+
+    #[test]
+    fn connections_have_their_own_sessions() {
+        let dir = TempDir::new("server").unwrap();
+        let mut database = Database::init(dir.path().to_str().unwrap().to_string(), &"admin".to_string(), &"password".to_string()).unwrap();
+        let admin = database.authenticate("admin".to_string(), "password".to_string()).unwrap();
+        database.create_user(&admin, "user".to_string(), "password".to_string(), Permissions::User()).unwrap();
+
+        let server = Server::bind(database, "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            (BufReader::new(stream.try_clone().unwrap()), stream)
+        };
+        let send = |(reader, writer): &mut (BufReader<TcpStream>, TcpStream), line: &str| -> Value {
+            writeln!(writer, "{}", line).unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            serde_json::from_str(&reply).unwrap()
+        };
+
+        let mut first = connect();
+        assert_eq!(send(&mut first, "GET a")["ok"], json!(false));
+        assert_eq!(send(&mut first, "LOGIN user password")["ok"], json!(true));
+        send(&mut first, "NEW test");
+        send(&mut first, "SELECT test");
+        send(&mut first, "INSERT a {\"b\":1}");
+        assert_eq!(send(&mut first, "GET a")["value"], json!({"b": 1}));
+        assert_eq!(send(&mut first, "INSERT \"c d\" {\n  \"e\": [1,\n 2]\n}")["ok"], json!(true));
+        assert_eq!(send(&mut first, "GET \"c d\"")["value"], json!({"e": [1, 2]}));
+
+        let mut second = connect();
+        send(&mut second, "LOGIN user password");
+        assert_eq!(send(&mut second, "GET a")["ok"], json!(false));
+        send(&mut second, "SELECT test");
+        assert_eq!(send(&mut second, "GET a")["value"], json!({"b": 1}));
+
+        // the connection that wrote is checkpointed when it closes, the one that only read isn't
+        std::mem::drop(first);
+        let (wal, manifest) = (dir.path().join("wal.log"), dir.path().join("manifest"));
+        assert!((0..200).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            fs::metadata(&wal).is_ok_and(|metadata| metadata.len() == 0)
+        }));
+        let checkpointed = fs::read(&manifest).unwrap();
+        std::mem::drop(second);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fs::read(&manifest).unwrap(), checkpointed);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::auth::Permissions;
+use crate::database::DatabaseState;
+use crate::wal::WALEntry;
 
 use serde::{Serialize, Deserialize};
 
@@ -6,5 +8,9 @@ use serde::{Serialize, Deserialize};
 pub struct Session {
     pub user: String,
     pub permissions: Permissions,
+    pub state: DatabaseState,
+    // writes made since BEGIN, nothing is logged or applied until COMMIT
+    pub transaction: Option<Vec<WALEntry>>,
+    // logged in with a read-only API token, so nothing gets written whatever the user could do
+    pub read_only: bool,
 }
-
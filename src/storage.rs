@@ -0,0 +1,175 @@
+use serde::{Serialize, Deserialize};
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+};
+
+use crate::collections::Collection;
+use crate::errors::DatabaseError;
+use crate::schema::Schema;
+
+// A snapshot is the magic, the header, the collection, its indexed paths and its schema as JSON
+// text. Collection files written before snapshots carried a header are a bare bincode Collection
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DBSNAP01";
+
+// Written last when the collections are saved, it says which generation of snapshots is current
+// and how far into the WAL they go. Anything in the WAL past wal_lsn still has to be replayed
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Manifest {
+    pub generation: u64,
+    pub wal_lsn: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct SnapshotHeader {
+    pub generation: u64,
+    // the last WAL record this snapshot already contains
+    pub wal_lsn: u64,
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> Result<Manifest, DatabaseError> {
+        match fs::read(format!("{}/manifest", path)) {
+            Ok(contents) => Ok(bincode::deserialize(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), DatabaseError> {
+        write_atomic(format!("{}/manifest", path), &bincode::serialize(self)?)
+    }
+}
+
+pub fn encode_snapshot(header: SnapshotHeader, collection: &Collection) -> Result<Vec<u8>, DatabaseError> {
+    let mut encoded = SNAPSHOT_MAGIC.to_vec();
+    bincode::serialize_into(&mut encoded, &header)?;
+    bincode::serialize_into(&mut encoded, collection)?;
+    bincode::serialize_into(&mut encoded, &collection.index_paths())?;
+    bincode::serialize_into(&mut encoded, &collection.schema().map(|schema| schema.source().to_string()))?;
+    Ok(encoded)
+}
+
+// Errors name the file and the byte offset that bincode got to before it gave up
+pub fn decode_snapshot(file: &str, contents: &[u8]) -> Result<(SnapshotHeader, Collection), DatabaseError> {
+    let (headed, mut reader) = match contents.strip_prefix(SNAPSHOT_MAGIC.as_slice()) {
+        Some(reader) => (true, reader),
+        None => (false, contents),
+    };
+    let corrupt = |reader: &[u8], e: bincode::Error| DatabaseError::CorruptData {
+        file: file.to_string(),
+        offset: (contents.len() - reader.len()) as u64,
+        reason: e.to_string(),
+    };
+
+    let header = match headed {
+        true => bincode::deserialize_from(&mut reader).map_err(|e| corrupt(reader, e))?,
+        false => SnapshotHeader::default(),
+    };
+    let mut collection: Collection = bincode::deserialize_from(&mut reader).map_err(|e| corrupt(reader, e))?;
+    if headed {
+        let paths: Vec<String> = bincode::deserialize_from(&mut reader).map_err(|e| corrupt(reader, e))?;
+        for path in paths {
+            collection.create_index(path);
+        }
+        let schema: Option<String> = bincode::deserialize_from(&mut reader).map_err(|e| corrupt(reader, e))?;
+        if let Some(schema) = schema {
+            let schema = serde_json::from_str(&schema).map_err(DatabaseError::from).and_then(Schema::parse)
+                .map_err(|e| DatabaseError::CorruptData { file: file.to_string(), offset: 0, reason: format!("schema: {}", e) })?;
+            collection.set_schema(Some(schema));
+        }
+    }
+    if !reader.is_empty() {
+        return Err(DatabaseError::CorruptData {
+            file: file.to_string(),
+            offset: (contents.len() - reader.len()) as u64,
+            reason: "trailing bytes after the collection".to_string(),
+        })
+    }
+    Ok((header, collection))
+}
+
+// Writes to a temporary file beside the destination, fsyncs it and renames it into place so
+// whoever reads the file next sees either all of the old contents or all of the new
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), DatabaseError> {
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&temp)?;
+
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::mem::drop(file);
+
+    fs::rename(&temp, path)?;
+
+    // the rename itself only survives a crash once the directory is synced
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use crate::collections::Collection;
+    use crate::errors::DatabaseError;
+    use crate::schema::Schema;
+    use crate::storage::{decode_snapshot, encode_snapshot, write_atomic, SnapshotHeader};
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut collection = Collection::new("test".to_string());
+        collection.insert("a".to_string(), json!({"b": 1}));
+        collection.create_index("b".to_string());
+        collection.set_schema(Some(Schema::parse(json!({"required": ["b"]})).unwrap()));
+
+        let encoded = encode_snapshot(SnapshotHeader { generation: 3, wal_lsn: 9 }, &collection).unwrap();
+        let (header, decoded) = decode_snapshot("test.db", &encoded).unwrap();
+        assert_eq!((header.generation, header.wal_lsn), (3, 9));
+        assert_eq!(decoded.get("a".to_string()), Some(json!({"b": 1})));
+        assert_eq!(decoded.index_paths(), vec!["b"]);
+        assert_eq!(decoded.schema().map(|schema| schema.source().clone()), Some(json!({"required": ["b"]})));
+
+        // files from before the header existed still load
+        let (header, decoded) = decode_snapshot("test.db", &bincode::serialize(&collection).unwrap()).unwrap();
+        assert_eq!(header.generation, 0);
+        assert_eq!(decoded.name, "test");
+    }
+
+    #[test]
+    fn corrupt_snapshot_names_file_and_offset() {
+        let collection = Collection::new("test".to_string());
+        let mut encoded = encode_snapshot(SnapshotHeader::default(), &collection).unwrap();
+        encoded.truncate(encoded.len() - 2);
+
+        match decode_snapshot("test.db", &encoded) {
+            Err(DatabaseError::CorruptData { file, offset, .. }) => {
+                assert_eq!(file, "test.db");
+                assert!(offset > 8 && offset <= encoded.len() as u64);
+            }
+            other => panic!("expected corruption error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let dir = TempDir::new("storage").unwrap();
+        let path = dir.path().join("file");
+        write_atomic(&path, b"a much longer first version").unwrap();
+        write_atomic(&path, b"short").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"short");
+        assert!(!dir.path().join("file.tmp").exists());
+    }
+}
@@ -0,0 +1,220 @@
+use serde_json::{Map, Number, Value};
+
+use crate::errors::DatabaseError;
+
+// the object a path ends in and the name of the field in it, as found by parent
+type Field<'a> = (&'a mut Map<String, Value>, &'a str);
+
+// What UPDATE does to a document. A patch made up only of $ operators runs each of them, anything
+// else is a JSON merge patch (RFC 7396) where objects merge, null removes a field and any other
+// value replaces what was there
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    Merge(Value),
+    Operators(Vec<Operator>),
+}
+
+// Paths are dotted like address.city, the same as index paths
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+    Set(String, Value),
+    Unset(String),
+    Inc(String, Number),
+    Push(String, Value),
+    // takes out every element equal to the value
+    Pull(String, Value),
+}
+
+impl Update {
+    pub fn parse(patch: Value) -> Result<Update, DatabaseError> {
+        let Value::Object(fields) = &patch else {
+            return Ok(Update::Merge(patch))
+        };
+        let operators = fields.keys().filter(|field| field.starts_with('$')).count();
+        if operators == 0 {
+            return Ok(Update::Merge(patch))
+        }
+        if operators != fields.len() {
+            return Err(DatabaseError::syntax("An update can't mix $ operators with plain fields"))
+        }
+
+        let mut parsed = Vec::new();
+        for (operator, arguments) in fields {
+            // $unset only needs the paths, so a list of them will do
+            if let ("$unset", Value::Array(paths)) = (operator.as_str(), arguments) {
+                for path in paths {
+                    let path = path.as_str().ok_or(DatabaseError::syntax("$unset expects paths as strings"))?;
+                    parsed.push(Operator::Unset(path.to_string()));
+                }
+                continue
+            }
+            let arguments = arguments.as_object()
+                .ok_or(DatabaseError::syntax(format!("{} expects an object of paths and values", operator)))?;
+            for (path, value) in arguments {
+                let path = path.clone();
+                parsed.push(match operator.as_str() {
+                    "$set" => Operator::Set(path, value.clone()),
+                    "$unset" => Operator::Unset(path),
+                    "$inc" => match value {
+                        Value::Number(amount) => Operator::Inc(path, amount.clone()),
+                        _ => return Err(DatabaseError::syntax(format!("$inc on {} expects a number", path))),
+                    },
+                    "$push" => Operator::Push(path, value.clone()),
+                    "$pull" => Operator::Pull(path, value.clone()),
+                    other => return Err(DatabaseError::syntax(format!("Unknown update operator {}, expected $set, $unset, $inc, $push or $pull", other))),
+                });
+            }
+        }
+        Ok(Update::Operators(parsed))
+    }
+
+    // The document as it is after the update, errors name the path that couldn't be changed
+    pub fn apply(&self, document: Value) -> Result<Value, DatabaseError> {
+        match self {
+            Update::Merge(patch) => Ok(merge(document, patch)),
+            Update::Operators(operators) => operators.iter().try_fold(document, |mut document, operator| {
+                operator.apply(&mut document)?;
+                Ok(document)
+            }),
+        }
+    }
+}
+
+impl Operator {
+    fn apply(&self, document: &mut Value) -> Result<(), DatabaseError> {
+        match self {
+            Operator::Set(path, value) => {
+                let (object, field) = parent(document, path, true)?.unwrap();
+                object.insert(field.to_string(), value.clone());
+            }
+            Operator::Unset(path) => {
+                if let Some((object, field)) = parent(document, path, false)? {
+                    object.remove(field);
+                }
+            }
+            Operator::Inc(path, amount) => {
+                let (object, field) = parent(document, path, true)?.unwrap();
+                let current = object.get(field).cloned().unwrap_or(Value::from(0));
+                let Value::Number(current) = current else {
+                    return Err(failed(path, "isn't a number"))
+                };
+                let sum = match (current.as_i64(), amount.as_i64()) {
+                    (Some(current), Some(amount)) => Value::from(current.checked_add(amount).ok_or_else(|| failed(path, "would overflow"))?),
+                    _ => Number::from_f64(current.as_f64().unwrap_or_default() + amount.as_f64().unwrap_or_default())
+                        .map(Value::Number)
+                        .ok_or_else(|| failed(path, "would overflow"))?,
+                };
+                object.insert(field.to_string(), sum);
+            }
+            Operator::Push(path, value) => {
+                let (object, field) = parent(document, path, true)?.unwrap();
+                match object.entry(field).or_insert(Value::Array(Vec::new())) {
+                    Value::Array(items) => items.push(value.clone()),
+                    _ => return Err(failed(path, "isn't an array")),
+                }
+            }
+            Operator::Pull(path, value) => {
+                if let Some((object, field)) = parent(document, path, false)? {
+                    match object.get_mut(field) {
+                        Some(Value::Array(items)) => items.retain(|item| item != value),
+                        Some(_) => return Err(failed(path, "isn't an array")),
+                        None => (),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The object holding the last field of the path and the name of that field. Objects missing on
+// the way are made when create is set, otherwise there's nothing to change and it's None
+fn parent<'a>(document: &'a mut Value, path: &'a str, create: bool) -> Result<Option<Field<'a>>, DatabaseError> {
+    let (parents, field) = match path.rsplit_once('.') {
+        Some((parents, field)) => (Some(parents), field),
+        None => (None, path),
+    };
+
+    let mut current = document;
+    let mut walked = String::new();
+    for name in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        let object = current.as_object_mut().ok_or_else(|| failed(&walked, "isn't an object"))?;
+        if !object.contains_key(name) {
+            if !create {
+                return Ok(None)
+            }
+            object.insert(name.to_string(), Value::Object(Map::new()));
+        }
+        walked = match walked.is_empty() {
+            true => name.to_string(),
+            false => format!("{}.{}", walked, name),
+        };
+        current = object.get_mut(name).unwrap();
+    }
+    let object = current.as_object_mut().ok_or_else(|| failed(&walked, "isn't an object"))?;
+    Ok(Some((object, field)))
+}
+
+fn failed(path: &str, reason: &str) -> DatabaseError {
+    let path = match path.is_empty() {
+        true => "$".to_string(),
+        false => format!("$.{}", path),
+    };
+    DatabaseError::ValidationError { path, reason: reason.to_string() }
+}
+
+fn merge(document: Value, patch: &Value) -> Value {
+    let Value::Object(patch) = patch else {
+        return patch.clone()
+    };
+    let mut document = match document {
+        Value::Object(document) => document,
+        _ => Map::new(),
+    };
+    for (field, value) in patch {
+        match value {
+            Value::Null => { document.remove(field); }
+            value => {
+                let merged = merge(document.remove(field).unwrap_or(Value::Null), value);
+                document.insert(field.clone(), merged);
+            }
+        }
+    }
+    Value::Object(document)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::errors::DatabaseError;
+    use crate::update::Update;
+
+    #[test]
+    fn merge_patch_and_operators() {
+        let document = json!({"name": "a", "address": {"city": "Oslo", "zip": "0150"}, "tags": ["x", "y", "x"], "visits": 1});
+
+        let merged = Update::parse(json!({"address": {"zip": null, "country": "NO"}, "name": "b"})).unwrap().apply(document.clone()).unwrap();
+        assert_eq!(merged, json!({"name": "b", "address": {"city": "Oslo", "country": "NO"}, "tags": ["x", "y", "x"], "visits": 1}));
+
+        let update = Update::parse(json!({
+            "$inc": {"visits": 2, "stats.logins": 1},
+            "$set": {"address.city": "Rome"},
+            "$unset": ["name", "missing.field"],
+            "$push": {"tags": "z"},
+            "$pull": {"tags": "x"},
+        })).unwrap();
+        assert_eq!(update.apply(document.clone()).unwrap(),
+            json!({"address": {"city": "Rome", "zip": "0150"}, "tags": ["y", "z"], "visits": 3, "stats": {"logins": 1}}));
+        assert_eq!(Update::parse(json!({"$inc": {"visits": 0.5}})).unwrap().apply(document.clone()).unwrap()["visits"], json!(1.5));
+
+        assert!(matches!(Update::parse(json!({"$inc": {"name": 1}})).unwrap().apply(document.clone()),
+            Err(DatabaseError::ValidationError { path, .. }) if path == "$.name"));
+        assert!(matches!(Update::parse(json!({"$set": {"name.first": "a"}})).unwrap().apply(document),
+            Err(DatabaseError::ValidationError { path, .. }) if path == "$.name"));
+        assert!(Update::parse(json!({"$set": {"a": 1}, "b": 2})).is_err());
+        assert!(Update::parse(json!({"$rename": {"a": "b"}})).is_err());
+        assert!(Update::parse(json!({"$inc": {"a": "1"}})).is_err());
+    }
+}
@@ -2,19 +2,37 @@ use serde::{Serialize, Deserialize};
 
 use std::{
     fs,
+    io::Write,
 };
 
-use bincode::deserialize_from;
 use serde_json::Value;
-use std::io::BufReader;
 
-use crate::parser::Command;
+use crate::crypto::Cipher;
 use crate::errors::DatabaseError;
 
+// Every record on disk is framed as
+//
+//   [payload length: u32][lsn: u64][crc32: u32][payload: bincode WALRecord]
+//
+// all little endian, the crc covers the length, the lsn and the payload. This lets replay tell a
+// record that was only half written when the process died apart from one that was damaged later
+// on. In an encrypted database the payload is sealed with the lsn as associated data, so records can't be
+// moved around the log either.
+const HEADER_LEN: usize = 16;
+
+// A transaction is written as Begin, its writes and then Commit. Writes between a Begin and a
+// Commit that never made it to the log are thrown away on replay
 #[derive(Serialize, Deserialize, Debug)]
+enum WALRecord {
+    Write(WALEntry),
+    Begin(),
+    Commit(),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WALEntry {
     pub collection: String,
-    pub operation: String, 
+    pub operation: String,
     pub key: String,
     // needs to be a String as bincode doesnt work with Json values
     pub value: Option<String>,
@@ -23,80 +41,347 @@ pub struct WALEntry {
 impl WALEntry {
 
     pub fn new(collection: String, operation: String, key: String, value: Option<Value>) -> WALEntry {
-        WALEntry {collection, operation, key, value: serde_json::to_string(&value.unwrap()).ok() }
-    }
-
-    pub fn log(&self, path: &str) {
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .unwrap();
-
-        bincode::serialize_into(&mut file, self).unwrap();
+        WALEntry {collection, operation, key, value: value.map(|value| value.to_string()) }
     }
+}
 
-    pub fn convert_to_operation(&self) -> Command {
-        println!("{}", serde_json::from_str::<Value>(&self.value.as_ref().unwrap()).unwrap());
-        match self.operation.as_str() {
-            "INSERT" => Command::INSERT(self.key.to_string(), serde_json::from_str(&self.value.as_ref().unwrap()).unwrap()),
-            "GET" => Command::GET(self.key.to_string()),
-            "DELETE" => Command::DELETE(self.key.to_string()),
-            _ => Command::ERROR(),
-        }
-    }
+// What was found in the log when it was read back
+#[derive(Debug)]
+pub struct WALReplay {
+    pub entries: Vec<(u64, WALEntry)>,
+    // offset and length of an incomplete final record that was cut off the log
+    pub torn_tail: Option<(u64, u64)>,
+    // offset of a transaction that never committed and how many writes it held
+    pub uncommitted: Option<(u64, usize)>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WALManager {
     pub path: String,
+    next_lsn: u64,
+    #[serde(skip)]
+    cipher: Option<Cipher>,
 }
 
 impl WALManager {
-    pub fn new(path : String) -> Self {
+    pub fn new(path : String, cipher: Option<Cipher>) -> Self {
         let _ = fs::File::create_new(format!("{}/wal.log", &path));
 
-        WALManager{ path }
+        WALManager{ path, next_lsn: 1, cipher }
+    }
+
+    // Only safe once the log has been cleared, records already in it stay under the old key
+    pub fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+    }
+
+    // The lsn of the last record handed out, what a snapshot taken now would contain
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+
+    // Keeps lsns increasing across a cleared log
+    pub fn resume_after(&mut self, lsn: u64) {
+        self.next_lsn = self.next_lsn.max(lsn + 1);
+    }
+
+    fn log_path(&self) -> String {
+        format!("{}/wal.log", self.path)
+    }
+
+    // Appends a framed record and fsyncs it before returning the lsn it was given
+    pub fn append(&mut self, entry: &WALEntry) -> Result<u64, DatabaseError> {
+        let mut records = Vec::new();
+        let lsn = self.frame(&WALRecord::Write(entry.clone()), &mut records)?;
+        self.write(&records)?;
+        Ok(lsn)
+    }
+
+    // Appends a whole transaction with a single write and fsync, returning the lsn of its commit
+    pub fn append_batch(&mut self, entries: &[WALEntry]) -> Result<u64, DatabaseError> {
+        let mut records = Vec::new();
+        self.frame(&WALRecord::Begin(), &mut records)?;
+        for entry in entries {
+            self.frame(&WALRecord::Write(entry.clone()), &mut records)?;
+        }
+        let lsn = self.frame(&WALRecord::Commit(), &mut records)?;
+        self.write(&records)?;
+        Ok(lsn)
     }
 
-    //pub fn operate(&self, command: Command, collection: String) -> Option<WALEntry> {
-    //    let entry = match command {
-    //        Command::INSERT(key, value) => Some(WALEntry::new(collection, "INSERT".to_string(), key, Some(value))),
-    //        Command::GET(key) => Some(WALEntry::new(collection, "GET".to_string(), key, None)),
-    //        Command::DELETE(key) => Some(WALEntry::new(collection, "DELETE".to_string(), key, None)),
-    //        _ => None,
-    //    };
-    //
-    //    entry.as_ref().unwrap().log(self.path.as_str());
-    //    entry
-    //}
+    fn frame(&mut self, record: &WALRecord, out: &mut Vec<u8>) -> Result<u64, DatabaseError> {
+        let lsn = self.next_lsn;
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.seal(&bincode::serialize(record)?, &lsn.to_le_bytes())?,
+            None => bincode::serialize(record)?,
+        };
+
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&lsn.to_le_bytes());
+        out.extend_from_slice(&WALManager::checksum(payload.len() as u32, lsn, &payload).to_le_bytes());
+        out.extend_from_slice(&payload);
 
-    pub fn read_wal_log(&self) -> Result<Vec<WALEntry>, DatabaseError> { 
-        let log = fs::OpenOptions::new()
-            .read(true)
-            .open(format!("{}/wal.log", self.path))
-            .unwrap();
+        self.next_lsn += 1;
+        Ok(lsn)
+    }
 
+    fn write(&self, records: &[u8]) -> Result<(), DatabaseError> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path())?;
+
+        file.write_all(records)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    // Reads every committed write. A torn record at the end of the log is truncated away and
+    // reported, as is a transaction that never committed, a damaged record anywhere before that
+    // is an error as the log can't be trusted
+    pub fn read_wal_log(&mut self) -> Result<WALReplay, DatabaseError> {
+        let contents = fs::read(self.log_path())?;
 
-        let mut contents = BufReader::new(log);
         let mut entries = Vec::new();
+        let mut torn_tail = None;
+        let mut offset = 0;
+        // start of the open transaction and the writes buffered for it
+        let mut transaction: Option<(usize, Vec<(u64, WALEntry)>)> = None;
+
+        while offset < contents.len() {
+            if contents.len() - offset < HEADER_LEN {
+                torn_tail = Some(offset);
+                break
+            }
+
+            let header = &contents[offset..offset + HEADER_LEN];
+            let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let lsn = u64::from_le_bytes(header[4..12].try_into().unwrap());
+
+            let end = offset + HEADER_LEN + len;
+            if end > contents.len() {
+                // a crash only ever cuts off the last record, whole records after this one mean
+                // its length was damaged and everything from here on would be thrown away
+                if WALManager::has_record(&contents, offset + 1) {
+                    return Err(self.corrupt(offset, "length runs past the end of the log".to_string()))
+                }
+                torn_tail = Some(offset);
+                break
+            }
+
+            let payload = &contents[offset + HEADER_LEN..end];
+            if !WALManager::verify(&contents[offset..end]) {
+                // the last record failing its checksum is a write that never fully hit the disk
+                if end == contents.len() {
+                    torn_tail = Some(offset);
+                    break
+                }
+                return Err(self.corrupt(offset, "checksum mismatch".to_string()))
+            }
 
-        while let Ok(entry) = deserialize_from::<_, WALEntry>(&mut contents) {
-            entries.push(entry);
+            let payload = match &self.cipher {
+                Some(cipher) => cipher.open(payload, &lsn.to_le_bytes())
+                    .ok_or_else(|| self.corrupt(offset, "failed to decrypt".to_string()))?,
+                None => payload.to_vec(),
+            };
+            let record: WALRecord = bincode::deserialize(&payload)
+                .map_err(|e| self.corrupt(offset, e.to_string()))?;
+
+            match (record, transaction.as_mut()) {
+                (WALRecord::Write(entry), Some((_, writes))) => writes.push((lsn, entry)),
+                (WALRecord::Write(entry), None) => entries.push((lsn, entry)),
+                (WALRecord::Begin(), None) => transaction = Some((offset, Vec::new())),
+                (WALRecord::Commit(), Some(_)) => entries.extend(transaction.take().unwrap().1),
+                (WALRecord::Begin(), Some(_)) => return Err(self.corrupt(offset, "transaction started inside another".to_string())),
+                (WALRecord::Commit(), None) => return Err(self.corrupt(offset, "commit without a transaction".to_string())),
+            }
+
+            self.next_lsn = self.next_lsn.max(lsn + 1);
+            offset = end;
         }
 
-        Ok(entries)
+        let torn_tail = torn_tail.map(|torn| (torn as u64, (contents.len() - torn) as u64));
+        let uncommitted = transaction.as_ref().map(|(start, writes)| (*start as u64, writes.len()));
 
+        // an unfinished transaction is always at the end of the log, cut it off along with any
+        // torn record so later writes don't end up inside it
+        let keep = transaction.map(|(start, _)| start).or(torn_tail.map(|(torn, _)| torn as usize));
+        if let Some(keep) = keep {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .open(self.log_path())?;
+            file.set_len(keep as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok(WALReplay { entries, torn_tail, uncommitted })
+    }
+
+    pub fn size(&self) -> Result<u64, DatabaseError> {
+        Ok(fs::metadata(self.log_path())?.len())
+    }
+
+    // Empties the log once everything in it has been written to the collection files
+    pub fn clear(&self) -> Result<(), DatabaseError> {
+        let wal = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.log_path())?;
+
+        wal.sync_all()?;
+        Ok(())
+    }
+
+    fn corrupt(&self, offset: usize, reason: String) -> DatabaseError {
+        DatabaseError::CorruptData { file: self.log_path(), offset: offset as u64, reason }
+    }
+
+    fn checksum(len: u32, lsn: u64, payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len.to_le_bytes());
+        hasher.update(&lsn.to_le_bytes());
+        hasher.update(payload);
+        hasher.finalize()
+    }
+
+    // Whether a whole framed record checks out
+    fn verify(record: &[u8]) -> bool {
+        let len = u32::from_le_bytes(record[0..4].try_into().unwrap());
+        let lsn = u64::from_le_bytes(record[4..12].try_into().unwrap());
+        let crc = u32::from_le_bytes(record[12..16].try_into().unwrap());
+        WALManager::checksum(len, lsn, &record[HEADER_LEN..]) == crc
+    }
+
+    // Whether a whole record that checks out starts anywhere from offset on
+    fn has_record(contents: &[u8], from: usize) -> bool {
+        (from..contents.len().saturating_sub(HEADER_LEN - 1)).any(|start| {
+            let len = u32::from_le_bytes(contents[start..start + 4].try_into().unwrap()) as usize;
+            start + HEADER_LEN + len <= contents.len() && WALManager::verify(&contents[start..start + HEADER_LEN + len])
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::wal::WALEntry;
-    use crate::parser::Command;
+    use std::fs;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    use crate::wal::{WALEntry, WALManager};
+    use crate::errors::DatabaseError;
+
+    fn entry(key: &str) -> WALEntry {
+        WALEntry::new("test".to_string(), "INSERT".to_string(), key.to_string(), Some(serde_json::json!(5)))
+    }
 
     #[test]
     fn wal_log() {
+        let dir = TempDir::new("wal").unwrap();
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        assert_eq!(wal.append(&entry("a")).unwrap(), 1);
+        assert_eq!(wal.append(&entry("b")).unwrap(), 2);
+
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        let replay = wal.read_wal_log().unwrap();
+        let keys: Vec<_> = replay.entries.iter().map(|(lsn, e)| (*lsn, e.key.as_str())).collect();
+        assert_eq!(keys, vec![(1, "a"), (2, "b")]);
+        assert!(replay.torn_tail.is_none());
+        assert_eq!(wal.append(&entry("c")).unwrap(), 3);
+    }
+
+    #[test]
+    fn wal_torn_tail_is_truncated() {
+        let dir = TempDir::new("wal").unwrap();
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        wal.append(&entry("a")).unwrap();
+        let good_len = fs::metadata(dir.path().join("wal.log")).unwrap().len();
+        wal.append(&entry("b")).unwrap();
+
+        // simulate a crash half way through the second record
+        let file = fs::OpenOptions::new().write(true).open(dir.path().join("wal.log")).unwrap();
+        file.set_len(good_len + 10).unwrap();
+
+        let replay = wal.read_wal_log().unwrap();
+        assert_eq!(replay.entries.len(), 1);
+        assert_eq!(replay.torn_tail, Some((good_len, 10)));
+        assert_eq!(fs::metadata(dir.path().join("wal.log")).unwrap().len(), good_len);
+    }
+
+    #[test]
+    fn wal_corrupt_record_is_an_error() {
+        let dir = TempDir::new("wal").unwrap();
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        wal.append(&entry("a")).unwrap();
+        wal.append(&entry("b")).unwrap();
+
+        let mut contents = fs::read(dir.path().join("wal.log")).unwrap();
+        let last = contents.len() / 2 - 1;
+        contents[last] ^= 0xff;
+        fs::File::create(dir.path().join("wal.log")).unwrap().write_all(&contents).unwrap();
+
+        match wal.read_wal_log() {
+            Err(DatabaseError::CorruptData { offset, .. }) => assert_eq!(offset, 0),
+            other => panic!("expected corruption error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wal_damaged_length_is_not_a_torn_tail() {
+        let dir = TempDir::new("wal").unwrap();
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        wal.append(&entry("a")).unwrap();
+        let second = fs::metadata(dir.path().join("wal.log")).unwrap().len() as usize;
+        wal.append(&entry("b")).unwrap();
+        wal.append(&entry("c")).unwrap();
+
+        // the second record's length now runs past the end of the log, with the third after it
+        let mut contents = fs::read(dir.path().join("wal.log")).unwrap();
+        contents[second + 3] = 0x7f;
+        fs::File::create(dir.path().join("wal.log")).unwrap().write_all(&contents).unwrap();
+
+        match wal.read_wal_log() {
+            Err(DatabaseError::CorruptData { offset, .. }) => assert_eq!(offset, second as u64),
+            other => panic!("expected corruption error, got {:?}", other),
+        }
+        assert_eq!(fs::read(dir.path().join("wal.log")).unwrap(), contents);
+
+        // a length changed to one that still fits fails the checksum it's now part of
+        contents[second + 3] = 0;
+        contents[second] -= 1;
+        fs::File::create(dir.path().join("wal.log")).unwrap().write_all(&contents).unwrap();
+        assert!(matches!(wal.read_wal_log(), Err(DatabaseError::CorruptData { .. })));
+
+        // a crc that leaves the length out doesn't pass
+        let mut record = [1u32.to_le_bytes().as_slice(), 7u64.to_le_bytes().as_slice(), &[0; 4], b"x"].concat();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&record[4..12]);
+        hasher.update(b"x");
+        record[12..16].copy_from_slice(&hasher.finalize().to_le_bytes());
+        assert!(!WALManager::verify(&record));
+        record[12..16].copy_from_slice(&WALManager::checksum(1, 7, b"x").to_le_bytes());
+        assert!(WALManager::verify(&record));
+    }
+
+    #[test]
+    fn wal_uncommitted_batch_is_dropped() {
+        let dir = TempDir::new("wal").unwrap();
+        let mut wal = WALManager::new(dir.path().to_str().unwrap().to_string(), None);
+        wal.append(&entry("a")).unwrap();
+        wal.append_batch(&[entry("b"), entry("c")]).unwrap();
+        let committed_len = fs::metadata(dir.path().join("wal.log")).unwrap().len();
+        wal.append_batch(&[entry("d"), entry("e")]).unwrap();
+
+        // lose the commit marker of the second batch
+        let len = fs::metadata(dir.path().join("wal.log")).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(dir.path().join("wal.log")).unwrap();
+        file.set_len(len - 4).unwrap();
+
+        let replay = wal.read_wal_log().unwrap();
+        let keys: Vec<_> = replay.entries.iter().map(|(_, e)| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        assert_eq!(replay.uncommitted, Some((committed_len, 2)));
+        assert_eq!(fs::metadata(dir.path().join("wal.log")).unwrap().len(), committed_len);
     }
 }